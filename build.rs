@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -7,19 +7,103 @@ fn main() {
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
 
+    // extract the kernel's function symbols; they are handed to the kernel as its ramdisk
+    let symbols_path = out_dir.join("kernel.syms");
+    write_symbol_table(&kernel, &symbols_path);
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
+        .set_ramdisk(&symbols_path)
         .create_disk_image(&uefi_path)
         .unwrap();
 
     // create a BIOS disk image
     let bios_path = out_dir.join("bios.img");
     bootloader::BiosBoot::new(&kernel)
+        .set_ramdisk(&symbols_path)
         .create_disk_image(&bios_path)
         .unwrap();
 
     // pass the disk image paths as env variables to the
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
-}
\ No newline at end of file
+}
+
+// ELF64 constants
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+/// Writes the kernel's function symbols as a table sorted by address.
+/// Layout (little endian), mirrored by `kernel/src/symbols.rs`:
+///   magic "SAMUXSYM", count: u32, reserved: u32,
+///   count * { addr: u64, size: u32, name_offset: u32, name_len: u32, reserved: u32 },
+///   the names (UTF-8, not terminated)
+fn write_symbol_table(kernel: &Path, out_path: &Path) {
+    let elf = std::fs::read(kernel).expect("failed to read kernel ELF");
+
+    let u16_at = |off: usize| u16::from_le_bytes(elf[off..off + 2].try_into().unwrap());
+    let u32_at = |off: usize| u32::from_le_bytes(elf[off..off + 4].try_into().unwrap());
+    let u64_at = |off: usize| u64::from_le_bytes(elf[off..off + 8].try_into().unwrap());
+
+    assert_eq!(&elf[..4], b"\x7fELF", "kernel is not an ELF file");
+
+    let section_headers = u64_at(0x28) as usize;
+    let section_header_size = u16_at(0x3A) as usize;
+    let section_count = u16_at(0x3C) as usize;
+
+    let mut symbols: Vec<(u64, u32, String)> = Vec::new();
+
+    for i in 0..section_count {
+        let header = section_headers + i * section_header_size;
+        if u32_at(header + 4) != SHT_SYMTAB {
+            continue;
+        }
+
+        let symtab_offset = u64_at(header + 0x18) as usize;
+        let symtab_size = u64_at(header + 0x20) as usize;
+        let entry_size = u64_at(header + 0x38) as usize;
+
+        // sh_link of the symbol table points at its string table
+        let strtab_header = section_headers + u32_at(header + 0x28) as usize * section_header_size;
+        let strtab_offset = u64_at(strtab_header + 0x18) as usize;
+
+        for sym in (symtab_offset..symtab_offset + symtab_size).step_by(entry_size) {
+            let name_offset = strtab_offset + u32_at(sym) as usize;
+            let info = elf[sym + 4];
+            let value = u64_at(sym + 8);
+            let size = u64_at(sym + 16);
+
+            if info & 0xF != STT_FUNC || value == 0 {
+                continue;
+            }
+
+            let name_end = elf[name_offset..].iter().position(|&b| b == 0).unwrap();
+            let mangled = String::from_utf8_lossy(&elf[name_offset..name_offset + name_end]);
+            let name = format!("{:#}", rustc_demangle::demangle(&mangled));
+
+            symbols.push((value, size as u32, name));
+        }
+    }
+
+    symbols.sort_by_key(|(addr, _, _)| *addr);
+    symbols.dedup_by_key(|(addr, _, _)| *addr);
+
+    let mut table = Vec::new();
+    let mut names = Vec::new();
+    table.extend_from_slice(b"SAMUXSYM");
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&0u32.to_le_bytes());
+
+    for (addr, size, name) in &symbols {
+        table.extend_from_slice(&addr.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        table.extend_from_slice(&0u32.to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&names);
+
+    std::fs::write(out_path, table).expect("failed to write symbol table");
+}
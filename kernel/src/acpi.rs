@@ -0,0 +1,164 @@
+use crate::memory::phys_to_virt;
+use alloc::vec::Vec;
+use core::ptr::read_unaligned;
+use x86_64::PhysAddr;
+
+// stolen off OSDev
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct MadtHeader {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+// MADT entry types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+
+// Local APIC flags
+const LAPIC_ENABLED: u32 = 1 << 0;
+const LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub processors: Vec<Processor>,
+}
+
+/// Reads a `T` out of physical memory through the complete physical mapping.
+unsafe fn read_phys<T: Copy>(addr: u64) -> T {
+    unsafe { read_unaligned(phys_to_virt(PhysAddr::new(addr)).as_ptr::<T>()) }
+}
+
+fn checksum_ok(addr: u64, len: usize) -> bool {
+    let bytes = phys_to_virt(PhysAddr::new(addr)).as_ptr::<u8>();
+    let sum = (0..len).fold(0u8, |sum, i| sum.wrapping_add(unsafe { *bytes.add(i) }));
+    sum == 0
+}
+
+// A table whose length covers at least its header and whose checksum
+// matches. Zero bytes would sum to 0 too.
+fn table_ok(addr: u64, header: &SdtHeader) -> bool {
+    let length = header.length as usize;
+    length >= size_of::<SdtHeader>() && checksum_ok(addr, length)
+}
+
+/// Walks the RSDT/XSDT for a table with the given signature and returns its physical address.
+fn find_table(rsdp_addr: u64, signature: &[u8; 4]) -> Option<u64> {
+    let rsdp: Rsdp = unsafe { read_phys(rsdp_addr) };
+    if &rsdp.signature != b"RSD PTR " || !checksum_ok(rsdp_addr, 20) {
+        return None;
+    }
+
+    // Prefer the XSDT (64-bit pointers) when the firmware provides one
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, 8)
+    } else {
+        (rsdp.rsdt_address as u64, 4)
+    };
+
+    let root_header: SdtHeader = unsafe { read_phys(root) };
+    if !table_ok(root, &root_header) {
+        return None;
+    }
+
+    let entries = (root_header.length as usize - size_of::<SdtHeader>()) / entry_size;
+    let entries_start = root + size_of::<SdtHeader>() as u64;
+
+    for i in 0..entries {
+        let entry_addr = entries_start + (i * entry_size) as u64;
+        let table = if entry_size == 8 {
+            unsafe { read_phys::<u64>(entry_addr) }
+        } else {
+            unsafe { read_phys::<u32>(entry_addr) as u64 }
+        };
+
+        let header: SdtHeader = unsafe { read_phys(table) };
+        if &header.signature == signature && table_ok(table, &header) {
+            return Some(table);
+        }
+    }
+
+    None
+}
+
+/// Parses the MADT ("APIC" table) to enumerate the usable processors.
+pub fn parse_madt(rsdp_addr: u64) -> Option<Madt> {
+    let madt_addr = find_table(rsdp_addr, b"APIC")?;
+    let madt: MadtHeader = unsafe { read_phys(madt_addr) };
+
+    let mut info = Madt {
+        local_apic_address: madt.local_apic_address as u64,
+        processors: Vec::new(),
+    };
+
+    let end = madt_addr + madt.header.length as u64;
+    let mut entry = madt_addr + size_of::<MadtHeader>() as u64;
+
+    while entry + 2 <= end {
+        let kind: u8 = unsafe { read_phys(entry) };
+        let len: u8 = unsafe { read_phys(entry + 1) };
+        if len < 2 {
+            break;
+        }
+
+        match kind {
+            MADT_LOCAL_APIC => {
+                let processor_id: u8 = unsafe { read_phys(entry + 2) };
+                let apic_id: u8 = unsafe { read_phys(entry + 3) };
+                let flags: u32 = unsafe { read_phys(entry + 4) };
+
+                if flags & (LAPIC_ENABLED | LAPIC_ONLINE_CAPABLE) != 0 {
+                    info.processors.push(Processor {
+                        processor_id,
+                        apic_id,
+                    });
+                }
+            }
+            MADT_LOCAL_APIC_OVERRIDE => {
+                info.local_apic_address = unsafe { read_phys(entry + 4) };
+            }
+            _ => {}
+        }
+
+        entry += len as u64;
+    }
+
+    Some(info)
+}
@@ -0,0 +1,157 @@
+use crate::memory;
+use crate::time::{self, Duration};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::{ApicBase, ApicBaseFlags};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Where the local APIC registers are mapped (uncached) in every address space.
+pub const LAPIC_VIRT_ADDR: u64 = 0x_4444_8888_0000;
+
+/// Vector the local APIC raises for spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+// Register offsets
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xB0;
+const REG_SVR: usize = 0xF0;
+const REG_ESR: usize = 0x280;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3E0;
+
+// Spurious Vector Register bits
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+
+// Interrupt Command Register bits
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+// LVT Timer bits
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// How long the timer's rate is measured against the TSC
+const TIMER_CALIBRATION: Duration = Duration::from_millis(10);
+
+// 0 until `init_bsp` has mapped the registers
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Maps the local APIC MMIO page and enables the APIC of the bootstrap CPU.
+/// `phys_addr` comes from the ACPI MADT.
+pub fn init_bsp(phys_addr: u64) {
+    let page = Page::containing_address(VirtAddr::new(LAPIC_VIRT_ADDR));
+    let frame = PhysFrame::containing_address(PhysAddr::new(phys_addr));
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+
+    memory::map_page(page, frame, flags).expect("failed to map local APIC");
+    LAPIC_BASE.store(LAPIC_VIRT_ADDR, Ordering::Release);
+
+    init_local();
+}
+
+/// Software-enables the local APIC of the calling CPU.
+/// The register page is shared, so the BSP must have run `init_bsp` first.
+pub fn init_local() {
+    unsafe {
+        let (frame, flags) = ApicBase::read();
+        ApicBase::write(frame, flags | ApicBaseFlags::LAPIC_ENABLE);
+
+        write(REG_SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+        write(REG_ESR, 0);
+    }
+}
+
+pub fn is_initialized() -> bool {
+    LAPIC_BASE.load(Ordering::Acquire) != 0
+}
+
+/// APIC ID of the calling CPU.
+pub fn id() -> u8 {
+    if !is_initialized() {
+        return 0;
+    }
+    (unsafe { read(REG_ID) } >> 24) as u8
+}
+
+pub fn end_of_interrupt() {
+    unsafe { write(REG_EOI, 0) };
+}
+
+/// Starts the local APIC timer of the calling CPU, raising `vector` every `period`.
+pub fn start_periodic_timer(vector: u8, period: Duration) {
+    unsafe {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+
+        // Let it count down from the maximum for a while to measure its rate
+        write(REG_LVT_TIMER, LVT_MASKED);
+        write(REG_TIMER_INITIAL, u32::MAX);
+        time::busy_wait(TIMER_CALIBRATION);
+        let ticks = (u32::MAX - read(REG_TIMER_CURRENT)) as u128;
+
+        let period_ticks = ticks * period.as_nanos() / TIMER_CALIBRATION.as_nanos();
+        write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+        write(
+            REG_TIMER_INITIAL,
+            period_ticks.clamp(1, u32::MAX as u128) as u32,
+        );
+    }
+}
+
+/// Sends an INIT IPI, putting the target CPU into wait-for-SIPI state.
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Sends a Startup IPI; the target begins executing in real mode at `vector_page * 4096`.
+pub fn send_startup(apic_id: u8, vector_page: u8) {
+    send_ipi(
+        apic_id,
+        ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | vector_page as u32,
+    );
+}
+
+pub fn send_nmi(apic_id: u8) {
+    send_ipi(apic_id, ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT);
+}
+
+pub fn send_fixed(apic_id: u8, vector: u8) {
+    send_ipi(
+        apic_id,
+        ICR_DELIVERY_FIXED | ICR_LEVEL_ASSERT | vector as u32,
+    );
+}
+
+fn send_ipi(apic_id: u8, command: u32) {
+    unsafe {
+        write(REG_ICR_HIGH, (apic_id as u32) << 24);
+        // Writing the low dword is what actually sends the IPI
+        write(REG_ICR_LOW, command);
+
+        while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+unsafe fn read(reg: usize) -> u32 {
+    let base = LAPIC_BASE.load(Ordering::Acquire) as usize;
+    unsafe { core::ptr::read_volatile((base + reg) as *const u32) }
+}
+
+unsafe fn write(reg: usize, value: u32) {
+    let base = LAPIC_BASE.load(Ordering::Acquire) as usize;
+    unsafe { core::ptr::write_volatile((base + reg) as *mut u32, value) }
+}
@@ -0,0 +1,128 @@
+use crate::{memory, symbols};
+use core::fmt;
+use x86_64::VirtAddr;
+
+/// Deepest stack walk we attempt.
+pub const MAX_FRAMES: usize = 32;
+
+/// Return addresses collected by walking the RBP chain.
+/// The kernel is built with `-C force-frame-pointers=yes` for this.
+#[derive(Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+    // Frame #0 is an instruction pointer rather than a return address
+    starts_at_rip: bool,
+}
+
+impl Backtrace {
+    /// Walks the stack of the caller.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        Self::from_frame_pointer(None, frame_pointer())
+    }
+
+    /// Walks a stack starting at frame pointer `rbp`. `rip` (for example the
+    /// faulting instruction of an exception) is reported as frame #0.
+    pub fn from_frame_pointer(rip: Option<u64>, rbp: u64) -> Self {
+        let mut backtrace = Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+            starts_at_rip: rip.is_some(),
+        };
+
+        if let Some(rip) = rip {
+            backtrace.push(rip);
+        }
+
+        let mut rbp = rbp;
+        while backtrace.len < MAX_FRAMES && is_readable_frame(rbp) {
+            // [rbp] = caller's rbp, [rbp + 8] = return address
+            let (next_rbp, return_addr) = unsafe {
+                let frame = rbp as *const u64;
+                (*frame, *frame.add(1))
+            };
+
+            if return_addr == 0 {
+                break;
+            }
+            backtrace.push(return_addr);
+
+            // The stack grows down, so callers always live at higher addresses
+            if next_rbp <= rbp {
+                break;
+            }
+            rbp = next_rbp;
+        }
+
+        backtrace
+    }
+
+    fn push(&mut self, addr: u64) {
+        self.frames[self.len] = addr;
+        self.len += 1;
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.len == 0 {
+            return writeln!(f, "  <no frames>");
+        }
+        for (i, &addr) in self.frames().iter().enumerate() {
+            write!(f, "  #{:<2} {:#018x}", i, addr)?;
+
+            // A return address may already belong to the next function if the call was
+            // the last instruction, so look up the byte before it
+            let lookup_addr = if i == 0 && self.starts_at_rip {
+                addr
+            } else {
+                addr - 1
+            };
+            if let Some(symbol) = symbols::lookup(lookup_addr) {
+                write!(f, " {}+{:#x}", symbol.name, addr - symbol.addr)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// The current value of RBP.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+/// For use inside an `extern "x86-interrupt"` handler: the frame pointer of
+/// the interrupted code, which the handler's prologue pushed.
+#[inline(always)]
+pub fn interrupted_frame_pointer() -> u64 {
+    let rbp = frame_pointer();
+    if !is_readable_frame(rbp) {
+        return 0;
+    }
+    unsafe { *(rbp as *const u64) }
+}
+
+/// Both words of the frame record at `rbp` must be mapped.
+fn is_readable_frame(rbp: u64) -> bool {
+    if rbp == 0 || !rbp.is_multiple_of(8) {
+        return false;
+    }
+    let Ok(start) = VirtAddr::try_new(rbp) else {
+        return false;
+    };
+    let Ok(end) = VirtAddr::try_new(rbp + 15) else {
+        return false;
+    };
+    memory::translate_addr(start).is_some() && memory::translate_addr(end).is_some()
+}
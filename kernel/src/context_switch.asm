@@ -0,0 +1,26 @@
+.global switch_context
+
+// fn switch_context(old_rsp: *mut u64, new_rsp: u64)
+// Saves the callee-saved registers of the running thread on its own stack,
+// stores the stack pointer to `old_rsp` and resumes the thread whose stack
+// pointer is `new_rsp`. Everything else was already saved by the caller
+// (the System V ABI) or by the interrupt that led here.
+// A new thread's stack is prepared in `thread.rs` to look the same.
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov [rdi], rsp
+    mov rsp, rsi
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
@@ -1,11 +1,12 @@
 // kernel/src/demo.rs
 use crate::framebuffer::WRITER;
-use core::future::Future;
-use core::pin::Pin;
-use core::task::Context;
-use core::task::Poll;
+use crate::task;
+use crate::time::{Duration, Instant};
 use x86_64::instructions::interrupts; // Import interrupts
 
+// How long each frame stays on screen
+const FRAME_TIME: Duration = Duration::from_millis(16);
+
 pub async fn bouncing_box() {
     let mut x = 100;
     let mut y = 100;
@@ -14,6 +15,8 @@ pub async fn bouncing_box() {
     let size = 40;
 
     loop {
+        let frame_started = Instant::now();
+
         // 1. Calculate new position
         // We scope this block so the Lock is released immediately after drawing!
 
@@ -58,32 +61,10 @@ pub async fn bouncing_box() {
             }
         }); // Lock is released here, interrupts re-enabled
 
-        // 2. Sleep/Yield
-        for _ in 0..100 {
-            yield_now().await;
+        // 2. Wait for the next frame, letting other tasks in whenever the
+        // poll budget runs out
+        while frame_started.elapsed() < FRAME_TIME {
+            task::consume_budget().await;
         }
     }
 }
-
-// --- Helper for the sleep loop ---
-struct YieldNow {
-    yielded: bool,
-}
-
-impl Future for YieldNow {
-    type Output = ();
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
-        if self.yielded {
-            Poll::Ready(())
-        } else {
-            self.yielded = true;
-            cx.waker().wake_by_ref();
-            Poll::Pending
-        }
-    }
-}
-
-fn yield_now() -> YieldNow {
-    YieldNow { yielded: false }
-}
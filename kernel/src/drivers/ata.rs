@@ -1,8 +1,20 @@
+use crate::interrupts::{self, IRQ_PRIMARY_ATA, IRQ_SECONDARY_ATA};
+use crate::task::timer;
+use crate::time::{Duration, Instant};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use futures_util::future::{Either, select};
+use futures_util::task::AtomicWaker;
 use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
 
 /// Standard sector size for ATA drives (512 bytes)
 pub const SECTOR_SIZE: usize = 512;
 
+/// How long the drive gets to finish a command (or a sector of one).
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
 // Command Constants
 const CMD_READ_SECTORS: u8 = 0x20;
 const CMD_WRITE_SECTORS: u8 = 0x30;
@@ -21,7 +33,77 @@ pub enum Bus {
     Secondary = 0x170,
 }
 
+impl Bus {
+    fn channel(&self) -> &'static Channel {
+        match self {
+            Bus::Primary => &CHANNELS[0],
+            Bus::Secondary => &CHANNELS[1],
+        }
+    }
+}
+
+// Set by a channel's IRQ handler and consumed by `IrqWait`
+struct Channel {
+    irq_pending: AtomicBool,
+    waker: AtomicWaker,
+}
+
+static CHANNELS: [Channel; 2] = [const {
+    Channel {
+        irq_pending: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    }
+}; 2];
+
+/// Hooks both ATA channels up to their IRQ lines, for `read_sectors` and
+/// `write_sectors`.
+pub fn init() {
+    interrupts::register_irq(IRQ_PRIMARY_ATA, primary_irq);
+    interrupts::register_irq(IRQ_SECONDARY_ATA, secondary_irq);
+}
+
+fn primary_irq() {
+    channel_irq(Bus::Primary);
+}
+
+fn secondary_irq() {
+    channel_irq(Bus::Secondary);
+}
+
+fn channel_irq(bus: Bus) {
+    // Reading the status register acknowledges the interrupt
+    let mut status_port = PortReadOnly::<u8>::new(bus as u16 + 7);
+    unsafe { status_port.read() };
+
+    let channel = bus.channel();
+    channel.irq_pending.store(true, Ordering::Release);
+    channel.waker.wake();
+}
+
+// Completes with the next interrupt of a channel
+struct IrqWait {
+    channel: &'static Channel,
+}
+
+impl Future for IrqWait {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.channel.irq_pending.swap(false, Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        self.channel.waker.register(cx.waker());
+        // The IRQ may have come in before the waker was registered
+        if self.channel.irq_pending.swap(false, Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
 pub struct AtaDrive {
+    bus: Bus,
     data_port: Port<u16>,
     error_port: PortReadOnly<u8>,
     sector_count_port: Port<u8>,
@@ -39,6 +121,7 @@ impl AtaDrive {
         let base = bus as u16;
 
         Self {
+            bus,
             data_port: Port::new(base),
             error_port: PortReadOnly::new(base + 1),
             sector_count_port: Port::new(base + 2),
@@ -57,26 +140,8 @@ impl AtaDrive {
             return Err("Buffer size does not match sector count");
         }
 
-        self.wait_busy();
-
-        // Determine Selection Byte
-        // 0xE0 = Master, 0xF0 = Slave
-        let drive_select = if self.is_master { 0xE0 } else { 0xF0 };
-
-        unsafe {
-            //
-            // Bit 4 selects drive (0=Master, 1=Slave)
-            // Bits 5 and 7 are usually fixed to 1 (0xA0 or 0xE0 for LBA)
-            self.drive_select_port
-                .write(drive_select | ((lba >> 24) & 0x0F) as u8);
-
-            self.sector_count_port.write(sectors);
-            self.lba_low_port.write(lba as u8);
-            self.lba_mid_port.write((lba >> 8) as u8);
-            self.lba_high_port.write((lba >> 16) as u8);
-
-            self.command_port.write(CMD_READ_SECTORS);
-        }
+        self.wait_busy()?;
+        self.send_command(CMD_READ_SECTORS, lba, sectors);
 
         // Read loop...
         for i in 0..sectors {
@@ -90,38 +155,132 @@ impl AtaDrive {
     }
 
     pub fn write(&mut self, lba: u32, sectors: u8, data: &[u16]) -> Result<(), &'static str> {
-        // ... length check ...
-        self.wait_busy();
+        if data.len() != (sectors as usize * 256) {
+            return Err("Buffer size does not match sector count");
+        }
+
+        self.wait_busy()?;
+        self.send_command(CMD_WRITE_SECTORS, lba, sectors);
 
+        // ... write loop ...
+        for i in 0..sectors {
+            self.poll_status()?;
+            for j in 0..256 {
+                unsafe {
+                    self.data_port.write(data[(i as usize * 256) + j]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads `buffer.len() / SECTOR_SIZE` sectors starting at `lba`. Waits
+    /// for the drive's interrupt instead of spinning, so other tasks run
+    /// in the meantime. Needs `init`.
+    pub async fn read_sectors(&mut self, lba: u32, buffer: &mut [u8]) -> Result<(), &'static str> {
+        let sectors = sector_count(buffer.len())?;
+
+        self.wait_busy()?;
+        self.bus
+            .channel()
+            .irq_pending
+            .store(false, Ordering::Release);
+        self.send_command(CMD_READ_SECTORS, lba, sectors);
+
+        // The drive interrupts once each sector is ready to be read
+        for sector in buffer.chunks_exact_mut(SECTOR_SIZE) {
+            self.wait_irq(true).await?;
+            for word in sector.chunks_exact_mut(2) {
+                let data = unsafe { self.data_port.read() };
+                word.copy_from_slice(&data.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `data` (whole sectors) starting at `lba`, waiting for the
+    /// drive's interrupts like `read_sectors`.
+    pub async fn write_sectors(&mut self, lba: u32, data: &[u8]) -> Result<(), &'static str> {
+        let sectors = sector_count(data.len())?;
+
+        self.wait_busy()?;
+        self.bus
+            .channel()
+            .irq_pending
+            .store(false, Ordering::Release);
+        self.send_command(CMD_WRITE_SECTORS, lba, sectors);
+
+        // The first sector is asked for without an interrupt; every sector
+        // written raises one, the last one once the command is done
+        self.poll_status()?;
+        for (i, sector) in data.chunks_exact(SECTOR_SIZE).enumerate() {
+            for word in sector.chunks_exact(2) {
+                unsafe { self.data_port.write(u16::from_le_bytes([word[0], word[1]])) };
+            }
+            let more = i + 1 < sectors as usize;
+            self.wait_irq(more).await?;
+        }
+        Ok(())
+    }
+
+    // Selects the drive, sets up the LBA28 address and starts `command`
+    fn send_command(&mut self, command: u8, lba: u32, sectors: u8) {
+        // Determine Selection Byte
+        // 0xE0 = Master, 0xF0 = Slave
         let drive_select = if self.is_master { 0xE0 } else { 0xF0 };
 
         unsafe {
+            //
+            // Bit 4 selects drive (0=Master, 1=Slave)
+            // Bits 5 and 7 are usually fixed to 1 (0xA0 or 0xE0 for LBA)
             self.drive_select_port
                 .write(drive_select | ((lba >> 24) & 0x0F) as u8);
-            // ... set other ports ...
+
             self.sector_count_port.write(sectors);
             self.lba_low_port.write(lba as u8);
             self.lba_mid_port.write((lba >> 8) as u8);
             self.lba_high_port.write((lba >> 16) as u8);
-            self.command_port.write(CMD_WRITE_SECTORS);
+
+            self.command_port.write(command);
         }
+    }
 
-        // ... write loop ...
-        for i in 0..sectors {
-            self.poll_status()?;
-            for j in 0..256 {
-                unsafe {
-                    self.data_port.write(data[(i as usize * 256) + j]);
+    // Waits for the channel's next interrupt and checks the outcome. With
+    // `want_data` the drive must be ready for a data transfer (DRQ), which
+    // every sector but the end of a write needs.
+    async fn wait_irq(&mut self, want_data: bool) -> Result<(), &'static str> {
+        let deadline = command_deadline();
+        loop {
+            let irq = IrqWait {
+                channel: self.bus.channel(),
+            };
+            if let Either::Right(_) = select(irq, timer::sleep_until(deadline)).await {
+                return Err("ATA timeout");
+            }
+
+            let status = unsafe { self.status_port.read() };
+            if status & STATUS_ERR != 0 {
+                return Err("ATA Drive Error");
+            }
+            // Otherwise a late interrupt from an earlier command
+            if status & STATUS_BSY == 0 {
+                if want_data && status & STATUS_DRQ == 0 {
+                    return Err("ATA drive has no data ready");
                 }
+                return Ok(());
             }
         }
-        Ok(())
     }
 
-    fn wait_busy(&mut self) {
+    fn wait_busy(&mut self) -> Result<(), &'static str> {
+        let deadline = command_deadline();
         while unsafe { self.status_port.read() } & STATUS_BSY != 0 {
+            if Instant::now() >= deadline {
+                return Err("ATA timeout");
+            }
             core::hint::spin_loop();
         }
+        Ok(())
     }
 
     fn poll_status(&mut self) -> Result<(), &'static str> {
@@ -129,6 +288,7 @@ impl AtaDrive {
             unsafe { self.status_port.read() };
         }
 
+        let deadline = command_deadline();
         loop {
             let status = unsafe { self.status_port.read() };
 
@@ -139,13 +299,17 @@ impl AtaDrive {
             if status & STATUS_BSY == 0 && status & STATUS_DRQ != 0 {
                 return Ok(());
             }
+
+            if Instant::now() >= deadline {
+                return Err("ATA timeout");
+            }
         }
     }
 
     /// Sends the IDENTIFY command to retrieve drive information.
     /// Returns a 256-word (512 byte) buffer of raw data.
     pub fn identify(&mut self) -> Result<[u16; 256], &'static str> {
-        self.wait_busy();
+        self.wait_busy()?;
 
         let drive_select = if self.is_master { 0xA0 } else { 0xB0 };
 
@@ -186,3 +350,17 @@ impl AtaDrive {
         Ok(sectors)
     }
 }
+
+fn command_deadline() -> Instant {
+    Instant::now()
+        .checked_add(COMMAND_TIMEOUT)
+        .unwrap_or(Instant::from_cycles(u64::MAX))
+}
+
+// Sectors in a buffer of `len` bytes, as one command can transfer them
+fn sector_count(len: usize) -> Result<u8, &'static str> {
+    if len == 0 || !len.is_multiple_of(SECTOR_SIZE) {
+        return Err("Buffer is not a whole number of sectors");
+    }
+    u8::try_from(len / SECTOR_SIZE).map_err(|_| "Too many sectors for one command")
+}
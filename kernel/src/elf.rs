@@ -0,0 +1,168 @@
+use crate::memory::{AddressSpace, USER_SPACE_END};
+use core::ptr;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+// Loads statically linked x86_64 ELF executables (ET_EXEC) into a fresh
+// user address space. Only PT_LOAD segments matter; there is no dynamic
+// linking, no relocation and no interpreter.
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3E;
+
+const PT_LOAD: u32 = 1;
+
+// Segment permissions
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct FileHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// A program loaded into its own address space, ready to run.
+pub struct Program {
+    pub address_space: AddressSpace,
+    pub entry: u64,
+}
+
+/// Checks the headers of the executable in `file` and maps its segments into
+/// a new address space.
+pub fn load(file: &[u8]) -> Result<Program, &'static str> {
+    let header: FileHeader = read(file, 0).ok_or("not an ELF file")?;
+    validate(&header)?;
+
+    let mut address_space = AddressSpace::new()?;
+    let mut loaded = false;
+
+    for index in 0..header.phnum as u64 {
+        let offset = header
+            .phoff
+            .checked_add(index * header.phentsize as u64)
+            .ok_or("program header out of bounds")?;
+        let segment: ProgramHeader =
+            read(file, offset as usize).ok_or("program header out of bounds")?;
+        if segment.kind != PT_LOAD || segment.memsz == 0 {
+            continue;
+        }
+
+        let data = segment_data(file, &segment)?;
+        let end = segment
+            .vaddr
+            .checked_add(segment.memsz)
+            .ok_or("segment address overflows")?;
+        if end > USER_SPACE_END {
+            return Err("segment outside user space");
+        }
+
+        address_space.map_range(
+            VirtAddr::new(segment.vaddr),
+            segment.memsz,
+            page_flags(&segment),
+        )?;
+        // The rest up to `memsz` (.bss) stays zeroed
+        address_space.write(VirtAddr::new(segment.vaddr), data)?;
+        loaded = true;
+    }
+
+    if !loaded {
+        return Err("no loadable segments");
+    }
+    if header.entry == 0 || header.entry >= USER_SPACE_END {
+        return Err("entry point outside user space");
+    }
+
+    Ok(Program {
+        address_space,
+        entry: header.entry,
+    })
+}
+
+fn validate(header: &FileHeader) -> Result<(), &'static str> {
+    if header.ident[..4] != ELF_MAGIC {
+        return Err("not an ELF file");
+    }
+    if header.ident[4] != ELFCLASS64 || header.ident[5] != ELFDATA2LSB {
+        return Err("not a 64-bit little endian ELF file");
+    }
+    if header.ident[6] != EV_CURRENT || header.version != EV_CURRENT as u32 {
+        return Err("unknown ELF version");
+    }
+    if header.machine != EM_X86_64 {
+        return Err("not an x86_64 executable");
+    }
+    if header.kind != ET_EXEC {
+        return Err("not a statically linked executable");
+    }
+    if header.phentsize as usize != size_of::<ProgramHeader>() {
+        return Err("unexpected program header size");
+    }
+    Ok(())
+}
+
+// The bytes of `segment` stored in the file
+fn segment_data<'a>(file: &'a [u8], segment: &ProgramHeader) -> Result<&'a [u8], &'static str> {
+    if segment.filesz > segment.memsz {
+        return Err("segment larger in the file than in memory");
+    }
+    if segment.align > 1
+        && (!segment.align.is_power_of_two()
+            || segment.vaddr % segment.align != segment.offset % segment.align)
+    {
+        return Err("misaligned segment");
+    }
+    let start = segment.offset as usize;
+    start
+        .checked_add(segment.filesz as usize)
+        .and_then(|end| file.get(start..end))
+        .ok_or("segment out of bounds")
+}
+
+fn page_flags(segment: &ProgramHeader) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    if segment.flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if segment.flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+// A header struct at `offset`, if the file is long enough
+fn read<T: Copy>(file: &[u8], offset: usize) -> Option<T> {
+    let bytes = file.get(offset..offset.checked_add(size_of::<T>())?)?;
+    Some(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
@@ -0,0 +1,310 @@
+use crate::fs::FILESYSTEM;
+use crate::fs::fat::{self, Fat32Driver};
+use crate::syscall::Errno;
+use crate::task::sync::AsyncMutexGuard;
+use crate::{print, process, thread, user};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+// Open files and the per-process tables of descriptors referring to them.
+// A disk file is read whole when it is opened, and written back whole when
+// a descriptor for it is closed after a write: the FAT driver can only
+// replace files. Descriptors made by `dup2` share one open file, offset
+// included.
+
+/// Descriptors a process can have open at once.
+pub const MAX_FILES: usize = 16;
+/// Largest disk file `write` grows a file to.
+pub const MAX_FILE_SIZE: usize = 1024 * 1024;
+
+// `open` flags, with their Linux values
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+pub const O_ACCMODE: u32 = 3;
+pub const O_CREAT: u32 = 0o100;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+
+// `lseek` origins
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+// `Stat::kind`
+pub const KIND_CONSOLE: u32 = 1;
+pub const KIND_FILE: u32 = 2;
+
+/// What `fstat` fills in.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    /// `KIND_CONSOLE` or `KIND_FILE`
+    pub kind: u32,
+    /// The flags it was opened with
+    pub flags: u32,
+    /// Size in bytes, 0 for the console
+    pub size: u64,
+}
+
+enum Backing {
+    /// Keyboard input in, screen out
+    Console,
+    Disk {
+        name: String,
+        data: Vec<u8>,
+        // Changed since it was read or written back
+        dirty: bool,
+    },
+}
+
+/// An open file, shared by the descriptors that refer to it.
+pub struct File {
+    backing: Backing,
+    flags: u32,
+    offset: u64,
+}
+
+pub type FileRef = Arc<Mutex<File>>;
+
+impl File {
+    /// The console, readable and writable.
+    pub fn console() -> Self {
+        File {
+            backing: Backing::Console,
+            flags: O_RDWR,
+            offset: 0,
+        }
+    }
+
+    /// Opens `name` in the root directory of the disk.
+    pub fn open(name: &str, flags: u32) -> Result<Self, Errno> {
+        if flags & O_ACCMODE > O_RDWR {
+            return Err(Errno::EINVAL);
+        }
+
+        let mut filesystem = lock_filesystem();
+        let fs = filesystem.as_mut().ok_or(Errno::EIO)?;
+        let (mut data, mut dirty) = match fs.read_file(name) {
+            Some(data) => (data, false),
+            // Created when it is written back
+            None if flags & O_CREAT != 0 && fat::is_valid_name(name) => (Vec::new(), true),
+            None if flags & O_CREAT != 0 => return Err(Errno::EINVAL),
+            None => return Err(Errno::ENOENT),
+        };
+        drop(filesystem);
+
+        if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY && !data.is_empty() {
+            data.clear();
+            dirty = true;
+        }
+        Ok(File {
+            backing: Backing::Disk {
+                name: String::from(name),
+                data,
+                dirty,
+            },
+            flags,
+            offset: 0,
+        })
+    }
+
+    /// Writes `bytes` at the offset, or at the end with `O_APPEND`.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<usize, Errno> {
+        if self.flags & O_ACCMODE == O_RDONLY {
+            return Err(Errno::EBADF);
+        }
+        match &mut self.backing {
+            Backing::Console => {
+                print!("{}", String::from_utf8_lossy(bytes));
+                Ok(bytes.len())
+            }
+            Backing::Disk { data, dirty, .. } => {
+                if self.flags & O_APPEND != 0 {
+                    self.offset = data.len() as u64;
+                }
+                let start = self.offset as usize;
+                let end = start
+                    .checked_add(bytes.len())
+                    .filter(|&end| end <= MAX_FILE_SIZE)
+                    .ok_or(Errno::EFBIG)?;
+                // A gap left by seeking past the end reads as zeros
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[start..end].copy_from_slice(bytes);
+                *dirty = true;
+                self.offset = end as u64;
+                Ok(bytes.len())
+            }
+        }
+    }
+
+    /// Moves the offset to `offset` past `whence` and returns it.
+    pub fn seek(&mut self, offset: i64, whence: u64) -> Result<u64, Errno> {
+        let Backing::Disk { data, .. } = &self.backing else {
+            return Err(Errno::ESPIPE);
+        };
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => self.offset,
+            SEEK_END => data.len() as u64,
+            _ => return Err(Errno::EINVAL),
+        };
+        self.offset = base.checked_add_signed(offset).ok_or(Errno::EINVAL)?;
+        Ok(self.offset)
+    }
+
+    pub fn stat(&self) -> Stat {
+        let (kind, size) = match &self.backing {
+            Backing::Console => (KIND_CONSOLE, 0),
+            Backing::Disk { data, .. } => (KIND_FILE, data.len() as u64),
+        };
+        Stat {
+            kind,
+            flags: self.flags,
+            size,
+        }
+    }
+
+    // Writes a changed disk file back
+    fn flush(&mut self) -> Result<(), Errno> {
+        let Backing::Disk { name, data, dirty } = &mut self.backing else {
+            return Ok(());
+        };
+        if !*dirty {
+            return Ok(());
+        }
+
+        let mut filesystem = lock_filesystem();
+        let fs = filesystem.as_mut().ok_or(Errno::EIO)?;
+        fs.write_file(name, data).map_err(|e| {
+            log::warn!("writing back {}: {}", name, e);
+            Errno::EIO
+        })?;
+        *dirty = false;
+        Ok(())
+    }
+}
+
+/// Reads from the offset of `file` into `buffer`. For the console, waits
+/// for at least one byte, without holding the lock of `file`: the other
+/// descriptors of the console (stdout, stderr) stay usable meanwhile.
+pub fn read(file: &FileRef, buffer: &mut [u8]) -> Result<usize, Errno> {
+    let mut guard = file.lock();
+    let file = &mut *guard;
+    if file.flags & O_ACCMODE == O_WRONLY {
+        return Err(Errno::EBADF);
+    }
+    let Backing::Disk { data, .. } = &file.backing else {
+        drop(guard);
+        return Ok(read_console(buffer));
+    };
+    let start = (file.offset as usize).min(data.len());
+    let count = buffer.len().min(data.len() - start);
+    buffer[..count].copy_from_slice(&data[start..start + count]);
+    file.offset += count as u64;
+    Ok(count)
+}
+
+/// Lets go of a descriptor's file, writing it back if it changed.
+pub fn close(file: FileRef) -> Result<(), Errno> {
+    file.lock().flush()
+}
+
+/// A process's descriptors, indexed by number.
+pub struct FdTable {
+    files: [Option<FileRef>; MAX_FILES],
+}
+
+impl FdTable {
+    /// A table with the console as 0, 1 and 2 (stdin, stdout and stderr).
+    pub fn new() -> Self {
+        let console = Arc::new(Mutex::new(File::console()));
+        let mut files = [const { None }; MAX_FILES];
+        for file in &mut files[..3] {
+            *file = Some(console.clone());
+        }
+        FdTable { files }
+    }
+
+    pub fn get(&self, fd: u64) -> Result<FileRef, Errno> {
+        self.files
+            .get(fd as usize)
+            .and_then(Option::clone)
+            .ok_or(Errno::EBADF)
+    }
+
+    /// Gives `file` the lowest free descriptor.
+    pub fn insert(&mut self, file: File) -> Result<u64, Errno> {
+        let fd = self
+            .files
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Errno::EMFILE)?;
+        self.files[fd] = Some(Arc::new(Mutex::new(file)));
+        Ok(fd as u64)
+    }
+
+    /// Frees descriptor `fd`. Pass the file to `close`.
+    pub fn remove(&mut self, fd: u64) -> Result<FileRef, Errno> {
+        self.files
+            .get_mut(fd as usize)
+            .and_then(Option::take)
+            .ok_or(Errno::EBADF)
+    }
+
+    /// Points `new` at the file `old` refers to. Returns the file `new`
+    /// referred to before, for `close`.
+    pub fn dup2(&mut self, old: u64, new: u64) -> Result<Option<FileRef>, Errno> {
+        let file = self.get(old)?;
+        let slot = self.files.get_mut(new as usize).ok_or(Errno::EBADF)?;
+        Ok(slot.replace(file))
+    }
+
+    /// Frees every descriptor, for a process that exits.
+    pub fn take_all(&mut self) -> Vec<FileRef> {
+        self.files.iter_mut().filter_map(Option::take).collect()
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The filesystem, for a thread: tasks hold the lock across awaits, so wait
+// for it by letting others run
+fn lock_filesystem() -> AsyncMutexGuard<'static, Option<Fat32Driver>> {
+    process::blocked(|| {
+        loop {
+            if let Some(filesystem) = FILESYSTEM.try_lock() {
+                return filesystem;
+            }
+            thread::pause();
+        }
+    })
+}
+
+// Waits for the first byte of keyboard input, then takes whatever else is there
+fn read_console(buffer: &mut [u8]) -> usize {
+    if buffer.is_empty() {
+        return 0;
+    }
+    process::blocked(|| {
+        let mut count = 0;
+        while count == 0 {
+            while count < buffer.len()
+                && let Some(byte) = user::pop_input()
+            {
+                buffer[count] = byte;
+                count += 1;
+            }
+            if count == 0 {
+                thread::pause();
+            }
+        }
+        count
+    })
+}
@@ -132,6 +132,12 @@ impl FrameBufferWriter {
         self.present();
     }
 
+    /// Moves the text cursor to pixel position (`x`, `y`).
+    pub fn set_cursor(&mut self, x: usize, y: usize) {
+        self.x_pos = x.min(self.info.width);
+        self.y_pos = y.min(self.info.height);
+    }
+
     pub fn width(&self) -> usize {
         self.info.width
     }
@@ -311,3 +317,16 @@ pub fn _print(args: fmt::Arguments) {
         }
     });
 }
+
+/// Clears the console. Like every user of `WRITER` outside interrupt
+/// handlers, it holds the lock with interrupts off, so the holder can't be
+/// preempted by a thread or handler that prints.
+pub fn clear_console() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if let Some(writer) = WRITER.lock().as_mut() {
+            writer.clear();
+        }
+    });
+}
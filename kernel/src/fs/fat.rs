@@ -1,4 +1,5 @@
 use crate::drivers::ata::AtaDrive;
+use crate::task;
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
@@ -71,6 +72,11 @@ impl DirectoryEntry {
         ((self.cluster_high as u32) << 16) | (self.cluster_low as u32)
     }
 
+    /// The first cluster of the file's data, None for an empty file.
+    pub fn first_cluster(&self) -> Option<u32> {
+        Some(self.get_cluster()).filter(|&cluster| cluster >= 2)
+    }
+
     pub fn get_filename(&self) -> String {
         let mut name = String::new();
         for &c in &self.name {
@@ -127,12 +133,7 @@ impl Fat32Driver {
             buf[i * 2 + 1] = ((word >> 8) & 0xFF) as u8;
         }
 
-        crate::serial_println!("DEBUG: Reading Sector 0...");
-        crate::serial_print!("Hex: ");
-        for i in 0..16 {
-            crate::serial_print!("{:02X} ", buf[i]);
-        }
-        crate::serial_println!();
+        log::debug!("Sector 0 starts with {:02X?}", &buf[..16]);
 
         let bpb = unsafe { &*(buf.as_ptr() as *const Bpb) };
 
@@ -172,8 +173,7 @@ impl Fat32Driver {
             *ptr
         };
 
-        let val = entry & 0x0FFF_FFFF;
-        if val >= 0x0FFF_FFF8 { None } else { Some(val) }
+        chain_next(entry)
     }
 
     fn read_cluster(&mut self, cluster: u32) -> Vec<u8> {
@@ -195,26 +195,11 @@ impl Fat32Driver {
 
         while let Some(cluster) = current_cluster {
             let data = self.read_cluster(cluster);
-
-            for chunk in data.chunks(32) {
-                if chunk.len() != 32 {
-                    break;
-                }
-                let entry = unsafe { &*(chunk.as_ptr() as *const DirectoryEntry) };
-
-                if entry.is_end() {
-                    return files;
-                }
-                if entry.is_free() {
-                    continue;
-                }
-                if entry.is_long_name() {
-                    continue;
-                }
-
-                if entry.attributes != 0x0F {
-                    files.push(entry.get_filename());
-                }
+            if !visit_entries(&data, |entry| {
+                files.push(entry.get_filename());
+                true
+            }) {
+                break;
             }
             current_cluster = self.next_cluster(cluster);
         }
@@ -225,26 +210,19 @@ impl Fat32Driver {
         let mut target_entry: Option<DirectoryEntry> = None;
         let mut current_cluster = Some(self.root_cluster);
 
-        'search: while let Some(cluster) = current_cluster {
+        while let Some(cluster) = current_cluster {
             let data = self.read_cluster(cluster);
-            for chunk in data.chunks(32) {
-                let entry = unsafe { &*(chunk.as_ptr() as *const DirectoryEntry) };
-                if entry.is_end() {
-                    break 'search;
-                }
-                if !entry.is_free() && !entry.is_long_name() {
-                    if entry.get_filename().eq_ignore_ascii_case(filename) {
-                        target_entry = Some(*entry);
-                        break 'search;
-                    }
-                }
+            if !visit_entries(&data, |entry| {
+                find_entry(entry, filename, &mut target_entry)
+            }) {
+                break;
             }
             current_cluster = self.next_cluster(cluster);
         }
 
         if let Some(entry) = target_entry {
             let mut file_data = Vec::new();
-            let mut current_cluster = Some(entry.get_cluster());
+            let mut current_cluster = entry.first_cluster();
 
             while let Some(cluster) = current_cluster {
                 let cluster_data = self.read_cluster(cluster);
@@ -259,6 +237,82 @@ impl Fat32Driver {
         None
     }
 
+    // Async counterparts of the above: the drive's interrupts wake the task
+    // instead of it spinning on the status port, so other tasks keep running.
+
+    async fn next_cluster_async(
+        &mut self,
+        current_cluster: u32,
+    ) -> Result<Option<u32>, &'static str> {
+        let fat_offset = current_cluster * 4;
+        let fat_sector = self.fat_start_sector + (fat_offset / 512);
+        let ent_offset = (fat_offset % 512) as usize;
+
+        let mut buf = [0u8; 512];
+        self.drive.read_sectors(fat_sector, &mut buf).await?;
+
+        let entry = u32::from_le_bytes(buf[ent_offset..ent_offset + 4].try_into().unwrap());
+        Ok(chain_next(entry))
+    }
+
+    async fn read_cluster_async(&mut self, cluster: u32) -> Result<Vec<u8>, &'static str> {
+        let mut data = vec![0u8; (self.sectors_per_cluster * 512) as usize];
+        self.drive
+            .read_sectors(self.cluster_to_lba(cluster), &mut data)
+            .await?;
+        Ok(data)
+    }
+
+    pub async fn list_root_async(&mut self) -> Result<Vec<String>, &'static str> {
+        let mut files = Vec::new();
+        let mut current_cluster = Some(self.root_cluster);
+
+        while let Some(cluster) = current_cluster {
+            let data = self.read_cluster_async(cluster).await?;
+            if !visit_entries(&data, |entry| {
+                files.push(entry.get_filename());
+                true
+            }) {
+                break;
+            }
+            current_cluster = self.next_cluster_async(cluster).await?;
+            task::consume_budget().await;
+            // A long cluster chain shouldn't hold up other tasks
+            task::consume_budget().await;
+        }
+        Ok(files)
+    }
+
+    pub async fn read_file_async(&mut self, filename: &str) -> Result<Vec<u8>, &'static str> {
+        let mut target_entry: Option<DirectoryEntry> = None;
+        let mut current_cluster = Some(self.root_cluster);
+
+        while let Some(cluster) = current_cluster {
+            let data = self.read_cluster_async(cluster).await?;
+            if !visit_entries(&data, |entry| {
+                find_entry(entry, filename, &mut target_entry)
+            }) {
+                break;
+            }
+            current_cluster = self.next_cluster_async(cluster).await?;
+            task::consume_budget().await;
+        }
+
+        let entry = target_entry.ok_or("File not found")?;
+        let mut file_data = Vec::new();
+        let mut current_cluster = entry.first_cluster();
+
+        while let Some(cluster) = current_cluster {
+            let cluster_data = self.read_cluster_async(cluster).await?;
+            file_data.extend_from_slice(&cluster_data);
+            current_cluster = self.next_cluster_async(cluster).await?;
+            task::consume_budget().await;
+        }
+
+        file_data.truncate(entry.size as usize);
+        Ok(file_data)
+    }
+
     fn write_sector_from_u8(&mut self, lba: u32, buffer: &[u8; 512]) {
         let mut raw_buffer = [0u16; 256];
         for (i, word) in raw_buffer.iter_mut().enumerate() {
@@ -402,6 +456,62 @@ impl Fat32Driver {
         Ok(())
     }
 
+    /// Replaces the contents of `filename`, creating it if it doesn't exist.
+    pub fn write_file(&mut self, filename: &str, data: &[u8]) -> Result<(), &'static str> {
+        short_name(filename)?;
+        if self.file_exists(filename) {
+            self.delete_file(filename)?;
+        }
+        if data.is_empty() {
+            // No clusters at all
+            return self.add_directory_entry(filename, 0, 0);
+        }
+        self.create_file(filename, data)
+    }
+
+    /// Removes `filename` from the root directory and frees its clusters.
+    pub fn delete_file(&mut self, filename: &str) -> Result<(), &'static str> {
+        let mut current_cluster = Some(self.root_cluster);
+
+        while let Some(cluster) = current_cluster {
+            let start_lba = self.cluster_to_lba(cluster);
+            for sector in 0..self.sectors_per_cluster {
+                let mut buf = [0u8; 512];
+                self.read_sector_into_u8(start_lba + sector, &mut buf);
+
+                for offset in (0..512).step_by(32) {
+                    let entry = unsafe { *(buf.as_ptr().add(offset) as *const DirectoryEntry) };
+                    if entry.is_end() {
+                        return Err("File not found");
+                    }
+                    if entry.is_free()
+                        || entry.is_long_name()
+                        || !entry.get_filename().eq_ignore_ascii_case(filename)
+                    {
+                        continue;
+                    }
+
+                    buf[offset] = 0xE5;
+                    self.write_sector_from_u8(start_lba + sector, &buf);
+                    self.free_chain(entry.first_cluster());
+                    return Ok(());
+                }
+            }
+            current_cluster = self.next_cluster(cluster);
+        }
+
+        Err("File not found")
+    }
+
+    // Marks every cluster of the chain starting at `first` free
+    fn free_chain(&mut self, first: Option<u32>) {
+        let mut current_cluster = first;
+        while let Some(cluster) = current_cluster {
+            current_cluster = self.next_cluster(cluster);
+            self.set_fat_entry(cluster, 0);
+        }
+    }
+
     pub fn file_exists(&mut self, filename: &str) -> bool {
         let mut current_cluster = Some(self.root_cluster);
 
@@ -432,24 +542,7 @@ impl Fat32Driver {
         size: u32,
     ) -> Result<(), &'static str> {
         // 1. Format Filename (8.3 format)
-        let mut name = [0x20u8; 8];
-        let mut ext = [0x20u8; 3];
-
-        let upper_name = filename.to_ascii_uppercase();
-        let parts: Vec<&str> = upper_name.split('.').collect();
-
-        if parts.is_empty() || parts[0].len() > 8 || (parts.len() > 1 && parts[1].len() > 3) {
-            return Err("Invalid filename (Must be 8.3 format)");
-        }
-
-        for (i, byte) in parts[0].bytes().enumerate() {
-            name[i] = byte;
-        }
-        if parts.len() > 1 {
-            for (i, byte) in parts[1].bytes().enumerate() {
-                ext[i] = byte;
-            }
-        }
+        let (name, ext) = short_name(filename)?;
 
         // 2. Find free slot in root directory
         let dir_sector = self.cluster_to_lba(self.root_cluster);
@@ -499,3 +592,68 @@ impl Fat32Driver {
         Ok(())
     }
 }
+
+/// Whether `filename` fits in a directory entry (8.3 format).
+pub fn is_valid_name(filename: &str) -> bool {
+    short_name(filename).is_ok()
+}
+
+// `filename` as the padded name and extension of a directory entry
+fn short_name(filename: &str) -> Result<([u8; 8], [u8; 3]), &'static str> {
+    let mut name = [0x20u8; 8];
+    let mut ext = [0x20u8; 3];
+
+    let upper_name = filename.to_ascii_uppercase();
+    let parts: Vec<&str> = upper_name.split('.').collect();
+
+    if parts[0].is_empty()
+        || parts[0].len() > 8
+        || parts.len() > 2
+        || (parts.len() > 1 && parts[1].len() > 3)
+    {
+        return Err("Invalid filename (Must be 8.3 format)");
+    }
+
+    for (i, byte) in parts[0].bytes().enumerate() {
+        name[i] = byte;
+    }
+    if parts.len() > 1 {
+        for (i, byte) in parts[1].bytes().enumerate() {
+            ext[i] = byte;
+        }
+    }
+    Ok((name, ext))
+}
+
+// The cluster after one whose FAT entry is `entry`, None at the end of the chain
+fn chain_next(entry: u32) -> Option<u32> {
+    let val = entry & 0x0FFF_FFFF;
+    if val >= 0x0FFF_FFF8 { None } else { Some(val) }
+}
+
+// Calls `visit` on every file entry in one cluster of a directory. Returns
+// false once the directory ends or `visit` returns false.
+fn visit_entries(data: &[u8], mut visit: impl FnMut(&DirectoryEntry) -> bool) -> bool {
+    for chunk in data.chunks_exact(32) {
+        let entry = unsafe { &*(chunk.as_ptr() as *const DirectoryEntry) };
+        if entry.is_end() {
+            return false;
+        }
+        if entry.is_free() || entry.is_long_name() {
+            continue;
+        }
+        if !visit(entry) {
+            return false;
+        }
+    }
+    true
+}
+
+// `visit_entries` callback that stops at `filename`
+fn find_entry(entry: &DirectoryEntry, filename: &str, found: &mut Option<DirectoryEntry>) -> bool {
+    if entry.get_filename().eq_ignore_ascii_case(filename) {
+        *found = Some(*entry);
+        return false;
+    }
+    true
+}
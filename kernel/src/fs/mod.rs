@@ -2,23 +2,25 @@ pub mod fat;
 
 use crate::drivers::ata::{AtaDrive, Bus};
 use crate::fs::fat::Fat32Driver;
-use crate::println;
-
+use crate::task::sync::AsyncMutex;
+use log::info;
 use spin::Mutex;
 
 pub static DRIVE: Mutex<Option<AtaDrive>> = Mutex::new(None);
-pub static FILESYSTEM: Mutex<Option<Fat32Driver>> = Mutex::new(None);
+// Tasks can hold it across disk I/O without stalling the executor
+pub static FILESYSTEM: AsyncMutex<Option<Fat32Driver>> = AsyncMutex::new(None);
 
 pub fn init_fs() {
     let drive = AtaDrive::new(Bus::Primary, false);
 
     let driver = Fat32Driver::new(drive);
 
-    // Lock the global mutex and move the drive instance into it
-    *FILESYSTEM.lock() = Some(driver);
+    // Nothing else runs yet, so the lock is free
+    *FILESYSTEM
+        .try_lock()
+        .expect("filesystem locked during boot") = Some(driver);
 
-    // Optional: Print status
-    println!("[Filesystem]: FAT32 Initialized on Primary Bus");
+    info!("FAT32 initialized on primary bus");
 }
 
 pub fn read_sector(lba: u32) -> Result<[u8; 512], &'static str> {
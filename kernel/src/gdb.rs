@@ -0,0 +1,648 @@
+use crate::interrupts::{self, TrapFrame};
+use crate::{memory, watchdog};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::rflags::RFlags;
+
+// A GDB remote serial protocol stub on COM2. Once attached, breakpoints and
+// single steps trap into `handle_trap`, which serves GDB until it resumes.
+// Only the trapping CPU stops; the others keep running.
+
+const COM2_PORT: u16 = 0x2F8;
+
+/// Largest packet accepted or sent, advertised to GDB in `qSupported`.
+const PACKET_SIZE: usize = 4096;
+
+const MAX_BREAKPOINTS: usize = 32;
+
+const INT3: u8 = 0xCC;
+// Sent by GDB to interrupt a running target (Ctrl-C)
+const INTERRUPT_CHAR: u8 = 0x03;
+// Signal reported with every stop
+const SIGTRAP: u8 = 5;
+
+// GDB's x86-64 register numbers, as far as the stub implements them:
+// 0-15 general purpose (in GDB's order), 16 rip, 17 eflags, 18-23 cs ss ds es fs gs
+const REGISTER_COUNT: usize = 24;
+const REG_RIP: usize = 16;
+const REG_EFLAGS: usize = 17;
+
+// The kernel is presented as a single thread for now
+const THREAD_ID: &str = "1";
+
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+static IN_SESSION: AtomicBool = AtomicBool::new(false);
+static KERNEL_IMAGE_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    saved_byte: u8,
+}
+
+struct Stub {
+    port: SerialPort,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    // Set by `c`/`s`: GDB is waiting for a stop reply
+    resumed: bool,
+    no_ack: bool,
+    // Static rather than on the stack, traps can hit on small task stacks
+    packet: [u8; PACKET_SIZE],
+    reply: Reply,
+}
+
+// Held for a whole session, so another CPU hitting a breakpoint waits its turn
+static STUB: Mutex<Stub> = Mutex::new(Stub {
+    port: unsafe { SerialPort::new(COM2_PORT) },
+    breakpoints: [None; MAX_BREAKPOINTS],
+    resumed: false,
+    no_ack: false,
+    packet: [0; PACKET_SIZE],
+    reply: Reply {
+        buf: [0; PACKET_SIZE],
+        len: 0,
+    },
+});
+
+/// Sets up COM2. `kernel_image_offset` is reported to GDB (`qOffsets`) so
+/// it can relocate the kernel's symbols by itself.
+pub fn init(kernel_image_offset: u64) {
+    KERNEL_IMAGE_OFFSET.store(kernel_image_offset, Ordering::Relaxed);
+    STUB.lock().port.init();
+    interrupts::register_irq(interrupts::IRQ_COM2, com2_irq);
+}
+
+pub fn is_attached() -> bool {
+    ATTACHED.load(Ordering::Acquire)
+}
+
+/// Whether some CPU is stopped in the stub right now.
+pub fn in_session() -> bool {
+    IN_SESSION.load(Ordering::Acquire)
+}
+
+pub fn kernel_image_offset() -> u64 {
+    KERNEL_IMAGE_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Starts a debugging session and stops right here until GDB connects to
+/// COM2 and resumes. Breakpoints keep trapping into the stub until GDB detaches.
+pub fn attach() {
+    ATTACHED.store(true, Ordering::Release);
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Serves GDB until it resumes the interrupted code.
+/// Called by the #BP and #DB handlers with interrupts disabled.
+pub fn handle_trap(frame: &mut TrapFrame) {
+    let mut stub = STUB.lock();
+    IN_SESSION.store(true, Ordering::Release);
+    serve(&mut stub, frame);
+    IN_SESSION.store(false, Ordering::Release);
+
+    // The time spent stopped is not a lockup
+    watchdog::touch();
+}
+
+fn serve(stub: &mut Stub, frame: &mut TrapFrame) {
+    let Stub {
+        port,
+        breakpoints,
+        resumed,
+        no_ack,
+        packet,
+        reply,
+    } = stub;
+
+    // Stepping is asked for again with every `s`
+    frame.rflags &= !RFlags::TRAP_FLAG.bits();
+
+    if *resumed {
+        *resumed = false;
+        reply.clear();
+        reply.push_stop(SIGTRAP);
+        send_packet(port, reply, *no_ack);
+    }
+
+    loop {
+        let len = receive_packet(port, packet, *no_ack);
+        let packet = &packet[..len];
+        reply.clear();
+
+        // Only changes how the transport behaves, after the reply
+        if packet == b"QStartNoAckMode" {
+            reply.push_str("OK");
+            send_packet(port, reply, *no_ack);
+            *no_ack = true;
+            continue;
+        }
+
+        let action = handle_packet(packet, reply, breakpoints, frame);
+        match action {
+            Action::Reply => send_packet(port, reply, *no_ack),
+            Action::Resume => {
+                *resumed = true;
+                return;
+            }
+            Action::Detach | Action::Quit => {
+                if matches!(action, Action::Detach) {
+                    send_packet(port, reply, *no_ack);
+                }
+                *no_ack = false;
+                return;
+            }
+        }
+    }
+}
+
+// Lets GDB interrupt a running kernel with Ctrl-C
+fn com2_irq() {
+    // The CPU in a session is polling the port itself
+    let Some(mut stub) = STUB.try_lock() else {
+        return;
+    };
+
+    let mut interrupted = false;
+    while let Ok(byte) = stub.port.try_receive() {
+        interrupted |= byte == INTERRUPT_CHAR;
+    }
+    drop(stub);
+
+    if interrupted && is_attached() {
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+enum Action {
+    /// Send the reply and wait for the next packet
+    Reply,
+    /// Return to the interrupted code; the reply is the next stop
+    Resume,
+    /// Send the reply and end the session
+    Detach,
+    /// End the session without replying
+    Quit,
+}
+
+fn handle_packet(
+    packet: &[u8],
+    reply: &mut Reply,
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+    frame: &mut TrapFrame,
+) -> Action {
+    let Some((&command, args)) = packet.split_first() else {
+        return Action::Reply;
+    };
+
+    match command {
+        b'?' => reply.push_stop(SIGTRAP),
+
+        // Read / write all registers
+        b'g' => {
+            for reg in 0..REGISTER_COUNT {
+                reply.push_hex_le(read_register(frame, reg), register_size(reg));
+            }
+        }
+        b'G' => {
+            let mut values = args;
+            for reg in 0..REGISTER_COUNT {
+                let digits = 2 * register_size(reg);
+                if values.len() < digits {
+                    break;
+                }
+                let (value, rest) = values.split_at(digits);
+                match parse_hex_le(value) {
+                    Some(value) => write_register(frame, reg, value),
+                    None => return reply.error(),
+                }
+                values = rest;
+            }
+            reply.push_str("OK");
+        }
+
+        // Read / write one register: `p n`, `P n=value`
+        b'p' => match parse_hex(args) {
+            Some(reg) if (reg as usize) < REGISTER_COUNT => {
+                let reg = reg as usize;
+                reply.push_hex_le(read_register(frame, reg), register_size(reg));
+            }
+            _ => {
+                reply.error();
+            }
+        },
+        b'P' => {
+            let Some((reg, value)) = split(args, b'=') else {
+                return reply.error();
+            };
+            match (parse_hex(reg), parse_hex_le(value)) {
+                (Some(reg), Some(value)) if (reg as usize) < REGISTER_COUNT => {
+                    write_register(frame, reg as usize, value);
+                    reply.push_str("OK");
+                }
+                _ => {
+                    reply.error();
+                }
+            }
+        }
+
+        // Read / write memory: `m addr,length`, `M addr,length:bytes`
+        b'm' => {
+            let Some((addr, len)) = parse_addr_len(args) else {
+                return reply.error();
+            };
+            // Hex doubles the size
+            let len = len.min((PACKET_SIZE / 2) as u64);
+            let mut read = 0;
+            while read < len && is_mapped(addr.wrapping_add(read)) {
+                let byte =
+                    unsafe { core::ptr::read_volatile(addr.wrapping_add(read) as *const u8) };
+                reply.push_hex_u8(byte);
+                read += 1;
+            }
+            if read == 0 && len != 0 {
+                reply.error();
+            }
+        }
+        b'M' => {
+            let Some((range, data)) = split(args, b':') else {
+                return reply.error();
+            };
+            let Some((addr, len)) = parse_addr_len(range) else {
+                return reply.error();
+            };
+            // A malformed length must not overflow either
+            let (Some(hex_len), Some(end)) = (len.checked_mul(2), addr.checked_add(len)) else {
+                return reply.error();
+            };
+            if data.len() as u64 != hex_len || !(addr..end).all(is_mapped) {
+                return reply.error();
+            }
+            for (i, digits) in data.chunks(2).enumerate() {
+                match parse_hex(digits) {
+                    Some(byte) => write_byte(addr + i as u64, byte as u8),
+                    None => return reply.error(),
+                }
+            }
+            reply.push_str("OK");
+        }
+
+        // Continue / single step, optionally from a new address
+        b'c' | b's' => {
+            if let Some(addr) = parse_hex(args) {
+                frame.rip = addr;
+            }
+            if command == b's' {
+                frame.rflags |= RFlags::TRAP_FLAG.bits();
+            }
+            return Action::Resume;
+        }
+
+        // Software breakpoints: `Z0,addr,kind`, `z0,addr,kind`
+        b'Z' | b'z' => {
+            let mut fields = args.split(|&b| b == b',');
+            let kind = fields.next();
+            let addr = fields.next().and_then(parse_hex);
+            match (kind, addr) {
+                (Some(&[b'0']), Some(addr)) => {
+                    let done = if command == b'Z' {
+                        insert_breakpoint(breakpoints, addr)
+                    } else {
+                        remove_breakpoint(breakpoints, addr)
+                    };
+                    if done {
+                        reply.push_str("OK");
+                    } else {
+                        reply.error();
+                    }
+                }
+                // Hardware breakpoints and watchpoints are not supported
+                (Some(_), Some(_)) => {}
+                _ => {
+                    reply.error();
+                }
+            }
+        }
+
+        // Detach / kill; the kernel keeps running either way
+        b'D' | b'k' => {
+            for slot in breakpoints.iter_mut() {
+                if let Some(breakpoint) = slot.take() {
+                    write_byte(breakpoint.addr, breakpoint.saved_byte);
+                }
+            }
+            frame.rflags &= !RFlags::TRAP_FLAG.bits();
+            ATTACHED.store(false, Ordering::Release);
+            if command == b'k' {
+                // GDB has already hung up
+                return Action::Quit;
+            }
+            reply.push_str("OK");
+            return Action::Detach;
+        }
+
+        b'H' | b'T' => reply.push_str("OK"),
+
+        b'q' => handle_query(args, reply),
+
+        // Anything else: the empty reply tells GDB it is not supported
+        _ => {}
+    }
+
+    Action::Reply
+}
+
+fn handle_query(query: &[u8], reply: &mut Reply) {
+    let name = query.split(|&b| b == b':').next().unwrap_or_default();
+    let offset = kernel_image_offset();
+
+    match name {
+        b"Supported" => {
+            let _ = write!(reply, "PacketSize={:x};QStartNoAckMode+", PACKET_SIZE);
+        }
+        b"Attached" => reply.push_str("1"),
+        b"C" => {
+            reply.push_str("QC");
+            reply.push_str(THREAD_ID);
+        }
+        b"fThreadInfo" => {
+            reply.push_str("m");
+            reply.push_str(THREAD_ID);
+        }
+        b"sThreadInfo" => reply.push_str("l"),
+        b"Offsets" => {
+            let _ = write!(
+                reply,
+                "Text={:x};Data={:x};Bss={:x}",
+                offset, offset, offset
+            );
+        }
+        _ => {}
+    }
+}
+
+fn register_size(reg: usize) -> usize {
+    if reg <= REG_RIP { 8 } else { 4 }
+}
+
+fn read_register(frame: &TrapFrame, reg: usize) -> u64 {
+    match reg {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        REG_RIP => frame.rip,
+        REG_EFLAGS => frame.rflags,
+        18 => frame.cs,
+        19 => frame.ss,
+        // DS, ES, FS and GS are always null in the kernel
+        _ => 0,
+    }
+}
+
+// Segment registers are read-only, a bad CS or SS would only fault on `iretq`
+fn write_register(frame: &mut TrapFrame, reg: usize, value: u64) {
+    let target = match reg {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        REG_RIP => &mut frame.rip,
+        REG_EFLAGS => {
+            frame.rflags = (frame.rflags & !0xFFFF_FFFF) | (value & 0xFFFF_FFFF);
+            return;
+        }
+        _ => return,
+    };
+    *target = value;
+}
+
+fn insert_breakpoint(breakpoints: &mut [Option<Breakpoint>], addr: u64) -> bool {
+    if breakpoints.iter().flatten().any(|b| b.addr == addr) {
+        return true;
+    }
+    if !is_mapped(addr) {
+        return false;
+    }
+    let Some(slot) = breakpoints.iter_mut().find(|slot| slot.is_none()) else {
+        return false;
+    };
+
+    let saved_byte = unsafe { core::ptr::read_volatile(addr as *const u8) };
+    write_byte(addr, INT3);
+    *slot = Some(Breakpoint { addr, saved_byte });
+    true
+}
+
+fn remove_breakpoint(breakpoints: &mut [Option<Breakpoint>], addr: u64) -> bool {
+    let Some(slot) = breakpoints
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|b| b.addr == addr))
+    else {
+        return false;
+    };
+
+    let breakpoint = slot.take().unwrap();
+    write_byte(breakpoint.addr, breakpoint.saved_byte);
+    true
+}
+
+fn is_mapped(addr: u64) -> bool {
+    VirtAddr::try_new(addr)
+        .ok()
+        .and_then(memory::translate_addr)
+        .is_some()
+}
+
+/// Writes through read-only mappings too (breakpoints go into kernel code).
+/// The caller checks that `addr` is mapped.
+fn write_byte(addr: u64, byte: u8) {
+    let cr0 = Cr0::read();
+    unsafe {
+        // Interrupts are off, nothing else runs on this CPU meanwhile
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        core::ptr::write_volatile(addr as *mut u8, byte);
+        Cr0::write(cr0);
+    }
+}
+
+// PACKETS
+
+/// Waits for the next well-formed packet and returns its length.
+fn receive_packet(port: &mut SerialPort, packet: &mut [u8; PACKET_SIZE], no_ack: bool) -> usize {
+    loop {
+        // Skip acks and stray interrupt requests up to the start of a packet
+        while port.receive() != b'$' {}
+
+        let mut len = 0;
+        let mut checksum: u8 = 0;
+        loop {
+            let byte = port.receive();
+            if byte == b'#' {
+                break;
+            }
+            checksum = checksum.wrapping_add(byte);
+            if len < PACKET_SIZE {
+                packet[len] = byte;
+                len += 1;
+            }
+        }
+
+        let expected = parse_hex(&[port.receive(), port.receive()]);
+        if no_ack {
+            return len;
+        }
+        if expected == Some(checksum as u64) {
+            port.send_raw(b'+');
+            return len;
+        }
+        port.send_raw(b'-');
+    }
+}
+
+/// Sends `reply` as a packet, retransmitting until GDB acknowledges it.
+fn send_packet(port: &mut SerialPort, reply: &Reply, no_ack: bool) {
+    let data = &reply.buf[..reply.len];
+    let checksum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+
+    loop {
+        port.send_raw(b'$');
+        for &byte in data {
+            port.send_raw(byte);
+        }
+        port.send_raw(b'#');
+        port.send_raw(HEX_DIGITS[(checksum >> 4) as usize]);
+        port.send_raw(HEX_DIGITS[(checksum & 0xF) as usize]);
+
+        if no_ack {
+            return;
+        }
+        loop {
+            match port.receive() {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for &byte in s.as_bytes() {
+            self.push(byte);
+        }
+    }
+
+    fn push_hex_u8(&mut self, byte: u8) {
+        self.push(HEX_DIGITS[(byte >> 4) as usize]);
+        self.push(HEX_DIGITS[(byte & 0xF) as usize]);
+    }
+
+    /// `size` bytes of `value` in target (little endian) byte order.
+    fn push_hex_le(&mut self, value: u64, size: usize) {
+        for byte in &value.to_le_bytes()[..size] {
+            self.push_hex_u8(*byte);
+        }
+    }
+
+    fn push_stop(&mut self, signal: u8) {
+        self.push(b'S');
+        self.push_hex_u8(signal);
+    }
+
+    fn error(&mut self) -> Action {
+        self.clear();
+        self.push_str("E01");
+        Action::Reply
+    }
+}
+
+impl fmt::Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+fn hex_value(digit: u8) -> Option<u64> {
+    (digit as char).to_digit(16).map(u64::from)
+}
+
+/// A big endian hex number, as used for addresses and lengths.
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits
+        .iter()
+        .try_fold(0u64, |value, &digit| Some((value << 4) | hex_value(digit)?))
+}
+
+/// A register value in target (little endian) byte order.
+fn parse_hex_le(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .enumerate()
+        .try_fold(0u64, |value, (i, byte)| {
+            Some(value | (parse_hex(byte)? << (8 * i)))
+        })
+}
+
+fn parse_addr_len(args: &[u8]) -> Option<(u64, u64)> {
+    let (addr, len) = split(args, b',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn split(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = bytes.iter().position(|&b| b == separator)?;
+    Some((&bytes[..at], &bytes[at + 1..]))
+}
@@ -1,3 +1,5 @@
+use alloc::boxed::Box;
+use alloc::vec;
 use lazy_static::lazy_static;
 use x86_64::VirtAddr;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
@@ -5,39 +7,24 @@ use x86_64::structures::tss::TaskStateSegment;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
-        };
-        tss
-    };
-}
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
+// The BSP's TSS. Mutable because `privilege_stack_table[0]`, where the CPU
+// switches to when an interrupt arrives from ring 3, follows the running thread.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let data_selector = gdt.append(Descriptor::kernel_data_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
-        let user_code_selector = gdt.append(Descriptor::user_code_segment());
-        let user_data_selector = gdt.append(Descriptor::user_data_segment());
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                data_selector,
-                tss_selector,
-                user_code_selector,
-                user_data_selector,
-            },
-        )
+        unsafe {
+            TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+                static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+                let stack_start = VirtAddr::from_ptr(&raw const STACK);
+                let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE as u64;
+                stack_end
+            };
+            build_gdt(&raw const TSS)
+        }
     };
 }
 
@@ -49,23 +36,70 @@ pub struct Selectors {
     pub user_data_selector: SegmentSelector,
 }
 
+// Every CPU gets the same layout, so the selectors in `GDT.1` are valid everywhere.
+// `tss` must stay valid forever.
+unsafe fn build_gdt(tss: *const TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.append(Descriptor::kernel_code_segment());
+    let data_selector = gdt.append(Descriptor::kernel_data_segment());
+    let tss_selector = gdt.append(unsafe { Descriptor::tss_segment_unchecked(tss) });
+    // `sysretq` expects user data right before user code
+    let user_data_selector = gdt.append(Descriptor::user_data_segment());
+    let user_code_selector = gdt.append(Descriptor::user_code_segment());
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            tss_selector,
+            user_code_selector,
+            user_data_selector,
+        },
+    )
+}
+
 pub fn init() {
+    GDT.0.load();
+    unsafe { load_segments(&GDT.1) };
+}
+
+/// Gives an application processor its own GDT and TSS (with its own
+/// double fault stack) and loads them.
+pub fn init_ap() {
+    let stack = vec![0u8; DOUBLE_FAULT_STACK_SIZE].leak();
+
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE as u64;
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+    let gdt: &'static (GlobalDescriptorTable, Selectors) =
+        Box::leak(Box::new(unsafe { build_gdt(tss) }));
+    gdt.0.load();
+    unsafe { load_segments(&gdt.1) };
+}
+
+unsafe fn load_segments(selectors: &Selectors) {
     use x86_64::instructions::segmentation::{CS, DS, ES, FS, GS, SS, Segment};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
-
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        SS::set_reg(GDT.1.data_selector);
+        CS::set_reg(selectors.code_selector);
+        SS::set_reg(selectors.data_selector);
         DS::set_reg(SegmentSelector(0));
         ES::set_reg(SegmentSelector(0));
         FS::set_reg(SegmentSelector(0));
         GS::set_reg(SegmentSelector(0));
-        load_tss(GDT.1.tss_selector);
+        load_tss(selectors.tss_selector);
     }
 }
 
+/// Sets the stack the BSP switches to when an interrupt arrives in ring 3.
+/// Threads, and so user programs, only run on the BSP.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { TSS.privilege_stack_table[0] = top };
+}
+
 // Helpers for Syscalls
 pub fn get_kernel_code_selector() -> SegmentSelector {
     GDT.1.code_selector
@@ -1,10 +1,18 @@
-use crate::serial_println;
+use crate::backtrace::{self, Backtrace};
+use crate::percpu::KernelGs;
+use crate::{gdb, monitor, percpu, process, serial_println, user, watchdog};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin::Mutex;
+use spin::{Mutex, RwLock};
 use x86_64::instructions::port::Port;
 use x86_64::registers::control::Cr2;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::{
+    HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+};
+use x86_64::{PrivilegeLevel, VirtAddr};
 
 // Solve Overlapping issue (PIC offsets start 1-15 and CPU exceptions 0-31)
 pub const PIC_1_OFFSET: u8 = 32; // 32 and onwards are free now
@@ -13,13 +21,50 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Number of IRQ lines behind the two chained PICs.
+pub const IRQ_LINES: usize = 16;
+
+// Well-known ISA IRQ lines
+pub const IRQ_TIMER: u8 = 0;
+pub const IRQ_KEYBOARD: u8 = 1;
+pub const IRQ_CASCADE: u8 = 2;
+pub const IRQ_COM2: u8 = 3;
+pub const IRQ_COM1: u8 = 4;
+pub const IRQ_MOUSE: u8 = 12;
+pub const IRQ_PRIMARY_ATA: u8 = 14;
+pub const IRQ_SECONDARY_ATA: u8 = 15;
+
+/// A driver's handler for an IRQ line.
+/// Runs in interrupt context with interrupts disabled, so it must not block.
+/// The dispatcher sends the EOI, handlers must not.
+pub type IrqHandler = fn();
+
+/// How many devices can share one IRQ line.
+pub const MAX_HANDLERS_PER_LINE: usize = 4;
+
+// Every handler on a line runs. Fixed slots, since drivers register
+// during `init_all`, before the heap exists.
+static IRQ_HANDLERS: [RwLock<[Option<IrqHandler>; MAX_HANDLERS_PER_LINE]>; IRQ_LINES] =
+    [const { RwLock::new([None; MAX_HANDLERS_PER_LINE]) }; IRQ_LINES];
+static IRQ_COUNTS: [AtomicU64; IRQ_LINES] = [const { AtomicU64::new(0) }; IRQ_LINES];
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
 
         // Set handlers for exceptions
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // The debugger traps go through trap_entry.asm, which saves every register
+        unsafe {
+            idt.breakpoint
+                .set_handler_addr(VirtAddr::new(breakpoint_entry as usize as u64));
+            idt.debug
+                .set_handler_addr(VirtAddr::new(debug_entry as usize as u64));
+        }
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
 
         // Double Fault needs special treatment with its own stack
         unsafe {
@@ -28,32 +73,133 @@ lazy_static! {
                 .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
         }
 
-        // Set handlers for hardware interrupts
-        idt[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_interrupt_handler);
+        // Every PIC line goes through a generic stub into `dispatch_irq`
+        for (line, stub) in IRQ_STUBS.iter().enumerate() {
+            idt[irq_vector(line as u8)].set_handler_fn(*stub);
+        }
+        idt[watchdog::WATCHDOG_VECTOR].set_handler_fn(watchdog_timer_handler);
+        idt[crate::apic::SPURIOUS_VECTOR].set_handler_fn(apic_spurious_handler);
 
         idt
     };
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard = PIC_1_OFFSET + 1,
+/// IDT vector an IRQ line is remapped to.
+pub fn irq_vector(line: u8) -> u8 {
+    PIC_1_OFFSET + line
+}
+
+pub fn init_idt() {
+    IDT.load();
+}
+
+/// Adds `handler` to the handlers of `line` and unmasks the line.
+pub fn register_irq(line: u8, handler: IrqHandler) {
+    assert!((line as usize) < IRQ_LINES, "invalid IRQ line {}", line);
+
+    // The lock is also taken in interrupt context
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS[line as usize].write();
+        let slot = handlers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .expect("too many handlers on one IRQ line");
+        *slot = Some(handler);
+    });
+    unmask_irq(line);
+}
+
+/// Number of interrupts seen on `line` since boot.
+pub fn irq_count(line: u8) -> u64 {
+    IRQ_COUNTS[line as usize].load(Ordering::Relaxed)
+}
+
+/// Number of handlers registered on `line`.
+pub fn irq_handler_count(line: u8) -> usize {
+    IRQ_HANDLERS[line as usize].read().iter().flatten().count()
 }
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
+/// Sets up both PICs with every line masked; `register_irq` unmasks lines as drivers claim them.
+pub fn init_pics() {
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.initialize();
+        pics.write_masks(u8::MAX, u8::MAX);
     }
-    fn as_usize(self) -> usize {
-        self as u8 as usize
+}
+
+/// Stops the PIC from delivering interrupts on `line`.
+pub fn mask_irq(line: u8) {
+    update_masks(|masks| masks[(line / 8) as usize] |= 1 << (line % 8));
+}
+
+/// Lets the PIC deliver interrupts on `line` again.
+pub fn unmask_irq(line: u8) {
+    update_masks(|masks| {
+        masks[(line / 8) as usize] &= !(1 << (line % 8));
+        if line >= 8 {
+            // Slave interrupts only arrive through the cascade line
+            masks[0] &= !(1 << IRQ_CASCADE);
+        }
+    });
+}
+
+pub fn is_irq_masked(line: u8) -> bool {
+    let masks = x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        PICS.lock().read_masks()
+    });
+    masks[(line / 8) as usize] & (1 << (line % 8)) != 0
+}
+
+fn update_masks(f: impl FnOnce(&mut [u8; 2])) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mut pics = PICS.lock();
+        let mut masks = pics.read_masks();
+        f(&mut masks);
+        pics.write_masks(masks[0], masks[1]);
+    });
+}
+
+// PIC command ports and the OCW3 command that selects the In-Service Register
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xA0;
+const PIC_READ_ISR: u8 = 0x0B;
+const PIC_EOI: u8 = 0x20;
+
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
+/// Spurious IRQ7/IRQ15 interrupts seen since boot.
+pub fn spurious_count() -> u64 {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+fn read_isr(command_port: u16) -> u8 {
+    let mut port: Port<u8> = Port::new(command_port);
+    unsafe {
+        port.write(PIC_READ_ISR);
+        port.read()
     }
 }
 
-pub fn init_idt() {
-    IDT.load();
+/// IRQ7 and IRQ15 are raised by the PICs for interrupts that vanished before
+/// being acknowledged. A real one has its bit set in the In-Service Register.
+fn is_spurious(line: u8) -> bool {
+    match line {
+        7 => read_isr(PIC_1_COMMAND) & (1 << 7) == 0,
+        15 => read_isr(PIC_2_COMMAND) & (1 << 7) == 0,
+        _ => false,
+    }
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// PIT interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn timer_tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
 }
 
 pub fn init_pit() {
@@ -70,49 +216,229 @@ pub fn init_pit() {
         data_port.write(0x00 as u8);
         data_port.write(0x00 as u8);
     }
+
+    register_irq(IRQ_TIMER, timer_tick);
+}
+
+/// Backtrace of the code an exception interrupted, starting at the faulting instruction.
+#[inline(always)]
+fn interrupted_backtrace(stack_frame: &InterruptStackFrame) -> Backtrace {
+    Backtrace::from_frame_pointer(
+        Some(stack_frame.instruction_pointer.as_u64()),
+        backtrace::interrupted_frame_pointer(),
+    )
 }
 
+/// Every register of the code a debugger trap interrupted, as pushed by
+/// trap_entry.asm below the CPU's interrupt frame. Changes made by the
+/// handler are restored on return.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+unsafe extern "C" {
+    fn breakpoint_entry();
+    fn debug_entry();
+}
+
+global_asm!(include_str!("trap_entry.asm"));
+
 // INTERRUPT HANDLERS
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+#[unsafe(no_mangle)]
+extern "C" fn breakpoint_handler(frame: &mut TrapFrame) {
+    if gdb::is_attached() {
+        gdb::handle_trap(frame);
+        return;
+    }
+    serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", frame);
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn debug_handler(frame: &mut TrapFrame) {
+    if gdb::is_attached() {
+        gdb::handle_trap(frame);
+        return;
+    }
+    // Nobody wants the single step anymore (the debugger detached)
+    frame.rflags &= !RFlags::TRAP_FLAG.bits();
 }
 
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    let _gs = KernelGs::enter(&stack_frame);
+    // A syscall copying bad user memory: make the copy fail instead
+    if let Some(fixup) = user::fixup(stack_frame.instruction_pointer.as_u64()) {
+        unsafe {
+            stack_frame
+                .as_mut()
+                .update(|frame| frame.instruction_pointer = VirtAddr::new(fixup));
+        }
+        return;
+    }
+    kill_faulting_process(&stack_frame, "page fault");
+
     serial_println!("EXCEPTION: PAGE FAULT");
     serial_println!("Accessed Address: {:?}", Cr2::read());
     serial_println!("Error Code: {:?}", error_code);
     serial_println!("{:#?}", stack_frame);
+    serial_println!("Backtrace:\n{}", interrupted_backtrace(&stack_frame));
+
+    panic!(
+        "Page fault at {:#x} accessing {:#x} ({:?})",
+        stack_frame.instruction_pointer.as_u64(),
+        Cr2::read_raw(),
+        error_code
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = KernelGs::enter(&stack_frame);
+    // The monitor reading an MSR that doesn't exist
+    if let Some(fixup) = monitor::fixup(stack_frame.instruction_pointer.as_u64()) {
+        unsafe {
+            stack_frame
+                .as_mut()
+                .update(|frame| frame.instruction_pointer = VirtAddr::new(fixup));
+        }
+        return;
+    }
+    kill_faulting_process(&stack_frame, "general protection fault");
+
+    serial_println!("Backtrace:\n{}", interrupted_backtrace(&stack_frame));
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}",
+        error_code, stack_frame
+    );
+}
 
-    panic!("Page fault");
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    kill_faulting_process(&stack_frame, "invalid opcode");
+
+    serial_println!("Backtrace:\n{}", interrupted_backtrace(&stack_frame));
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+// An exception in ring 3 only ends the process that caused it
+fn kill_faulting_process(stack_frame: &InterruptStackFrame, exception: &str) {
+    if stack_frame.code_segment.rpl() != PrivilegeLevel::Ring3 {
+        return;
+    }
+    log::warn!(
+        "process killed: {} at {:#x}",
+        exception,
+        stack_frame.instruction_pointer.as_u64()
+    );
+    // No kernel code was interrupted, so waiting for locks is fine
+    x86_64::instructions::interrupts::enable();
+    process::exit(process::EXIT_FAULT);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    let _gs = KernelGs::enter_paranoid();
+    serial_println!("Backtrace:\n{}", interrupted_backtrace(&stack_frame));
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+// Sent by the watchdog to a CPU that stopped taking interrupts
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter_paranoid();
+    crate::panic::halt_if_panicking();
+    watchdog::handle_nmi(&stack_frame, backtrace::interrupted_frame_pointer());
+}
+
+// Local APIC timer of the watchdog's watcher CPU
+extern "x86-interrupt" fn watchdog_timer_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    watchdog::check_hard_lockups();
+    crate::apic::end_of_interrupt();
+}
+
+// Spurious local APIC interrupts must not be acknowledged
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {}
+
 // PICs
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+fn dispatch_irq(line: u8) {
+    percpu!(stats).interrupts.fetch_add(1, Ordering::Relaxed);
+
+    if is_spurious(line) {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+        // The master did see the cascade line of a spurious IRQ15, so it still wants its EOI
+        if line == 15 {
+            unsafe { Port::new(PIC_1_COMMAND).write(PIC_EOI) };
+        }
+        return;
     }
-}
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
+    IRQ_COUNTS[line as usize].fetch_add(1, Ordering::Relaxed);
 
-    // Send the raw scancode to the queue
-    crate::task::keyboard::add_scancode(scancode);
+    for handler in IRQ_HANDLERS[line as usize].read().iter().flatten() {
+        handler();
+    }
 
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        PICS.lock().notify_end_of_interrupt(irq_vector(line));
     }
+
+    // The timer may have used up the running thread's quantum
+    crate::thread::preempt_if_needed();
+}
+
+// Lets handlers (the watchdog) see where the IRQ came in
+fn record_interrupted(stack_frame: &InterruptStackFrame, rbp: u64) {
+    let cpu = percpu!();
+    cpu.interrupted_rip
+        .store(stack_frame.instruction_pointer.as_u64(), Ordering::Relaxed);
+    cpu.interrupted_rbp.store(rbp, Ordering::Relaxed);
+}
+
+macro_rules! irq_stubs {
+    ($($name:ident => $line:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+                let _gs = KernelGs::enter(&stack_frame);
+                record_interrupted(&stack_frame, backtrace::interrupted_frame_pointer());
+                dispatch_irq($line);
+            }
+        )*
+
+        const IRQ_STUBS: [HandlerFunc; IRQ_LINES] = [$($name),*];
+    };
+}
+
+irq_stubs! {
+    irq0 => 0, irq1 => 1, irq2 => 2, irq3 => 3,
+    irq4 => 4, irq5 => 5, irq6 => 6, irq7 => 7,
+    irq8 => 8, irq9 => 9, irq10 => 10, irq11 => 11,
+    irq12 => 12, irq13 => 13, irq14 => 14, irq15 => 15,
 }
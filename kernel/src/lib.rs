@@ -3,50 +3,74 @@
 
 extern crate alloc;
 
+use log::info;
+
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
 pub mod demo;
 pub mod drivers;
+pub mod elf;
+pub mod file;
 pub mod framebuffer;
 pub mod fs;
+pub mod gdb;
 pub mod gdt;
 pub mod interrupts;
+pub mod logger;
 pub mod memory;
+pub mod monitor;
 pub mod panic;
+pub mod percpu;
+pub mod process;
 pub mod serial;
 pub mod shell;
+pub mod smp;
+pub mod symbols;
 pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod time;
+pub mod user;
+pub mod watchdog;
 
 pub fn init_all() {
     gdt::init();
-    println!("[INIT] GDT initialized.");
+    percpu::init_bsp();
+    info!("GDT and per-CPU area initialized");
+
+    syscall::init_syscall();
+    info!("Syscalls initialized");
 
     interrupts::init_idt();
-    println!("[INIT] IDT initialized.");
-
-    unsafe {
-        let mut pics = interrupts::PICS.lock();
-        pics.initialize();
-
-        // 0xFC = 1111 1100 (Binary)
-        // Zeros mean "Enabled". Ones mean "Disabled".
-        // Bit 0 (Timer) = 0
-        // Bit 1 (Keyboard) = 0
-        // All others = 1
-        pics.write_masks(0xFC, 0xFF);
-        // ---------------------
-    }
-    println!("[INIT] PICs initialized.");
+    info!("IDT initialized");
+
+    interrupts::init_pics();
+    info!("PICs initialized");
 
     interrupts::init_pit();
+    task::keyboard::init();
+    task::timer::init();
+    drivers::ata::init();
+    // Calibrate before enabling interrupts so the measurement isn't disturbed
+    time::init();
     x86_64::instructions::interrupts::enable();
-    println!("[INIT] PIT initialized and interrupts enabled.");
+    info!("PIT initialized and interrupts enabled");
+
+    let tsc_hz = time::tsc_frequency();
+    info!(
+        "TSC calibrated: {}.{:03} MHz (invariant: {})",
+        tsc_hz / 1_000_000,
+        (tsc_hz / 1_000) % 1_000,
+        time::tsc_is_invariant()
+    );
 
     // Keep the int3 here for now to be safe!
     x86_64::instructions::interrupts::int3();
 
     fs::init_fs();
-    println!("[INIT] Filesystem initialized.");
+    info!("Filesystem initialized");
 
-    serial_println!("All systems initialized.");
+    info!("All systems initialized");
 }
@@ -0,0 +1,229 @@
+use crate::time::{self, Instant};
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Backend for the `log` crate macros (`log::info!` and friends).
+// Every record that passes the global level is kept in a fixed ring buffer
+// (no heap, so logging works from early boot and interrupt handlers) and is
+// passed on to each sink whose own level admits it.
+
+/// Records kept for `dmesg`; the oldest are overwritten.
+pub const RING_ENTRIES: usize = 256;
+/// Longer messages are truncated.
+pub const MESSAGE_LEN: usize = 160;
+const TARGET_LEN: usize = 24;
+
+/// Where records go besides the ring buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sink {
+    Serial,
+    Console,
+}
+
+impl Sink {
+    pub const ALL: [Sink; 2] = [Sink::Serial, Sink::Console];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sink::Serial => "serial",
+            Sink::Console => "console",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Sink> {
+        Sink::ALL.into_iter().find(|sink| sink.name() == name)
+    }
+
+    fn level(&self) -> &'static AtomicUsize {
+        match self {
+            Sink::Serial => &SERIAL_LEVEL,
+            Sink::Console => &CONSOLE_LEVEL,
+        }
+    }
+}
+
+// LevelFilter as usize. The framebuffer only gets warnings by default, to keep the shell clean
+static SERIAL_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Debug as usize);
+static CONSOLE_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Warn as usize);
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Debug;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    time: Instant,
+    level: Level,
+    target: [u8; TARGET_LEN],
+    target_len: usize,
+    message: [u8; MESSAGE_LEN],
+    message_len: usize,
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry {
+        time: Instant::from_cycles(0),
+        level: Level::Trace,
+        target: [0; TARGET_LEN],
+        target_len: 0,
+        message: [0; MESSAGE_LEN],
+        message_len: 0,
+    };
+
+    fn target(&self) -> &str {
+        core::str::from_utf8(&self.target[..self.target_len]).unwrap_or("?")
+    }
+
+    fn message(&self) -> &str {
+        core::str::from_utf8(&self.message[..self.message_len]).unwrap_or("?")
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let uptime = time::uptime_at(self.time);
+        write!(
+            f,
+            "[{:>5}.{:06}] {:<5} {}: {}",
+            uptime.as_secs(),
+            uptime.subsec_micros(),
+            self.level,
+            self.target(),
+            self.message()
+        )
+    }
+}
+
+struct Ring {
+    entries: [Entry; RING_ENTRIES],
+    // Total records ever written; the newest is at `(written - 1) % RING_ENTRIES`
+    written: usize,
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring {
+    entries: [Entry::EMPTY; RING_ENTRIES],
+    written: 0,
+});
+
+/// Copies formatted text into a fixed buffer, dropping whatever doesn't fit.
+struct Truncating<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let mut utf8 = [0; 4];
+            let bytes = c.encode_utf8(&mut utf8).as_bytes();
+            if self.len + bytes.len() > self.buf.len() {
+                break;
+            }
+            self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+        Ok(())
+    }
+}
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut entry = Entry::EMPTY;
+        entry.time = Instant::now();
+        entry.level = record.level();
+
+        // Every target starts with "kernel::", leave it out
+        let target = record.target();
+        let target = target.strip_prefix("kernel::").unwrap_or(target);
+        let mut writer = Truncating {
+            buf: &mut entry.target,
+            len: 0,
+        };
+        let _ = writer.write_str(target);
+        entry.target_len = writer.len;
+
+        let mut writer = Truncating {
+            buf: &mut entry.message,
+            len: 0,
+        };
+        let _ = writer.write_fmt(*record.args());
+        entry.message_len = writer.len;
+
+        // Also written from interrupt handlers
+        interrupts::without_interrupts(|| {
+            let mut ring = RING.lock();
+            let index = ring.written % RING_ENTRIES;
+            ring.entries[index] = entry;
+            ring.written += 1;
+        });
+
+        if record.level() as usize <= Sink::Serial.level().load(Ordering::Relaxed) {
+            crate::serial_println!("{}", entry);
+        }
+        if record.level() as usize <= Sink::Console.level().load(Ordering::Relaxed) {
+            crate::println!("{}", entry);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger. Records made before this are dropped.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(DEFAULT_LEVEL);
+    }
+}
+
+/// Level above which records are dropped entirely (not even kept for `dmesg`).
+pub fn level() -> LevelFilter {
+    log::max_level()
+}
+
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+pub fn sink_level(sink: Sink) -> LevelFilter {
+    let level = sink.level().load(Ordering::Relaxed);
+    LevelFilter::iter()
+        .find(|filter| *filter as usize == level)
+        .unwrap_or(LevelFilter::Off)
+}
+
+pub fn set_sink_level(sink: Sink, level: LevelFilter) {
+    sink.level().store(level as usize, Ordering::Relaxed);
+}
+
+/// The buffered records at or above `level`, oldest first, formatted for display.
+pub fn dmesg(level: LevelFilter) -> Vec<String> {
+    let written = interrupts::without_interrupts(|| RING.lock().written);
+    let mut lines = Vec::new();
+
+    for n in written.saturating_sub(RING_ENTRIES)..written {
+        // Copy each entry out, formatting allocates and shouldn't hold up interrupts
+        let entry = interrupts::without_interrupts(|| {
+            let ring = RING.lock();
+            // Skip entries overwritten in the meantime
+            (ring.written - n <= RING_ENTRIES).then(|| ring.entries[n % RING_ENTRIES])
+        });
+        if let Some(entry) = entry.filter(|entry| entry.level <= level) {
+            lines.push(format!("{}", entry));
+        }
+    }
+    lines
+}
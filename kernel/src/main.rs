@@ -4,52 +4,85 @@
 extern crate alloc;
 
 use bootloader_api::{BootInfo, BootloaderConfig, config::Mapping, entry_point};
+use log::info;
 use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
 use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
 
 use kernel::allocator;
 use kernel::framebuffer::{self, WRITER};
+use kernel::gdb;
 use kernel::init_all;
+use kernel::logger;
 use kernel::memory::{self, BootInfoFrameAllocator};
 use kernel::println;
-use kernel::serial_println;
 use kernel::shell;
-use kernel::task::{Task, executor::Executor};
+use kernel::smp;
+use kernel::symbols;
+use kernel::task::{Priority, Task, executor::Executor};
+use kernel::thread;
+use kernel::watchdog;
 
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(Mapping::Dynamic);
+    // Keep the kernel and everything the bootloader maps for it in the upper
+    // half; the lower half is for user programs
+    config.mappings.dynamic_range_start = Some(0xFFFF_8000_0000_0000);
     config
 };
 
 entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
-    serial_println!("Kernel initialized successfully!\n");
+    logger::init();
+    info!("Kernel started");
+
+    // The build passes the kernel's symbol table as the ramdisk
+    if let Some(ramdisk_addr) = boot_info.ramdisk_addr.into_option() {
+        let ramdisk = unsafe {
+            core::slice::from_raw_parts(ramdisk_addr as *const u8, boot_info.ramdisk_len as usize)
+        };
+        symbols::init(ramdisk, boot_info.kernel_image_offset);
+    }
+
     init_all();
-    serial_println!("IDT initialized.\n");
+    gdb::init(boot_info.kernel_image_offset);
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    *memory::MAPPER.lock() = Some(mapper);
+    *memory::FRAME_ALLOCATOR.lock() = Some(frame_allocator);
 
     // Initialize Framebuffer
     if let Some(framebuffer) = boot_info.framebuffer.as_mut() {
         let info = framebuffer.info();
         let buffer = framebuffer.buffer_mut();
-        let mut writer = WRITER.lock();
-        *writer = Some(framebuffer::FrameBufferWriter::new(buffer, info));
+        let writer = framebuffer::FrameBufferWriter::new(buffer, info);
+        interrupts::without_interrupts(|| *WRITER.lock() = Some(writer));
     }
 
     println!("Hello World from the Framebuffer!");
 
+    smp::init(boot_info.rsdp_addr.into_option());
+    println!("{} CPU(s) online", smp::online_count());
+    watchdog::init();
+
+    // From here on this is the idle thread
+    thread::init();
+
     // --- RUN SHELL FIRST ---
     let executor = Executor::new();
-    executor.spawn(Task::new(shell::runshell()));
-    executor.spawn(Task::new(kernel::demo::bouncing_box()));
+    executor.spawn(Task::named("shell", shell::runshell()).with_priority(Priority::Interactive));
+    executor.spawn(
+        Task::named("bouncing_box", kernel::demo::bouncing_box())
+            .with_priority(Priority::Background),
+    );
 
-    // Note: The executor.run() below will loop forever.
-    executor.run();
+    // The async tasks all run in the executor thread
+    thread::spawn("executor", move || executor.run());
+    thread::idle_loop();
 }
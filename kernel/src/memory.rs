@@ -1,12 +1,40 @@
+use alloc::vec::Vec;
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::{
     PhysAddr, VirtAddr,
+    registers::control::Cr3,
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+        mapper::{MapToError, TranslateResult},
     },
 };
 
+/// Frames below 1MiB are never handed out; real-mode code such as the SMP
+/// trampoline needs to live there.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// End of the part of every address space that belongs to user programs.
+/// Everything above it is the kernel's and the same in all address spaces.
+pub const USER_SPACE_END: u64 = 0x0000_4000_0000_0000;
+
+// P4 entries below this one map user space
+const USER_P4_ENTRIES: usize = (USER_SPACE_END >> 39) as usize;
+
+// Set by kernel_main once the heap is up
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+pub static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+// The page table the bootloader left us, used by every kernel-only thread
+static KERNEL_P4: AtomicU64 = AtomicU64::new(0);
+
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_P4.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+
     unsafe {
         let level_4_table = active_level_4_table(physical_memory_offset);
         OffsetPageTable::new(level_4_table, physical_memory_offset)
@@ -14,8 +42,6 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
 }
 
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    use x86_64::registers::control::Cr3;
-
     let (level_4_table_frame, _) = Cr3::read();
 
     let phys = level_4_table_frame.start_address();
@@ -25,6 +51,87 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     unsafe { &mut *page_table_ptr }
 }
 
+/// Returns the virtual address through which `phys` is reachable in the
+/// bootloader's complete physical memory mapping.
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + phys.as_u64())
+}
+
+/// The kernel's own page table, active whenever no user program runs.
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_P4.load(Ordering::Relaxed)))
+}
+
+/// Translates a virtual address by walking the active page tables directly.
+/// Takes no locks, so it is safe to use from fault and panic handlers.
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    translate_with_flags(addr).map(|(phys, _)| phys)
+}
+
+/// Like `translate_addr`, but also returns the flags of the last table entry.
+pub fn translate_with_flags(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    if PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let table_indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut frame_addr = level_4_table_frame.start_address();
+    let mut leaf_flags = PageTableFlags::empty();
+
+    for (level, &index) in table_indexes.iter().enumerate() {
+        let table = unsafe { &*phys_to_virt(frame_addr).as_ptr::<PageTable>() };
+        let entry = &table[index];
+        let flags = entry.flags();
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+
+        // 1GiB pages end the walk at the P3 table, 2MiB pages at the P2 table
+        if flags.contains(PageTableFlags::HUGE_PAGE) {
+            let page_mask = match level {
+                1 => 0x3FFF_FFFF,
+                2 => 0x1F_FFFF,
+                _ => return None,
+            };
+            return Some((entry.addr() + (addr.as_u64() & page_mask), flags));
+        }
+
+        frame_addr = entry.addr();
+        leaf_flags = flags;
+    }
+
+    Some((frame_addr + u64::from(addr.page_offset()), leaf_flags))
+}
+
+/// Maps a single page using the global mapper and frame allocator.
+/// Mapping a page that already points at `frame` is not an error.
+pub fn map_page(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let mapper = mapper.as_mut().expect("memory not initialized");
+    let frame_allocator = frame_allocator.as_mut().expect("memory not initialized");
+
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(MapToError::PageAlreadyMapped(existing)) if existing == frame => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
 pub fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
@@ -53,6 +160,8 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryRegions,
     next: usize,
+    // Handed back by `deallocate_frame`, given out again first
+    free: Vec<PhysFrame>,
 }
 
 impl BootInfoFrameAllocator {
@@ -60,15 +169,26 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free: Vec::new(),
         }
     }
 
+    /// The memory map the bootloader passed to the kernel.
+    pub fn memory_map(&self) -> &'static MemoryRegions {
+        self.memory_map
+    }
+
+    /// Frames currently allocated.
+    pub fn allocated_frames(&self) -> usize {
+        self.next - self.free.len()
+    }
+
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // get usable regions from memory map
         let regions = self.memory_map.iter();
         let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.start..r.end);
+        // map each region to its address range, leaving low memory alone
+        let addr_ranges = usable_regions.map(|r| r.start.max(LOW_MEMORY_END)..r.end);
         // transform to an iterator of frame start addresses
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         // create `PhysFrame` types from the start addresses
@@ -78,8 +198,209 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free.pop() {
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
+
+// Must not be called before the heap is up
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free.push(frame);
+    }
+}
+
+/// A set of page tables for one user program. The user half is its own,
+/// the kernel half is shared with the kernel's page table: its P4 entries
+/// are copied when the address space is created.
+pub struct AddressSpace {
+    p4: PhysFrame,
+}
+
+impl AddressSpace {
+    /// An address space with nothing mapped in user space.
+    pub fn new() -> Result<AddressSpace, &'static str> {
+        let p4 = allocate_zeroed_frame()?;
+        let kernel =
+            unsafe { &*phys_to_virt(kernel_page_table().start_address()).as_ptr::<PageTable>() };
+        let table = unsafe { &mut *phys_to_virt(p4.start_address()).as_mut_ptr::<PageTable>() };
+        for index in USER_P4_ENTRIES..512 {
+            table[index] = kernel[index].clone();
+        }
+        Ok(AddressSpace { p4 })
+    }
+
+    /// Its P4 table, for CR3.
+    pub fn page_table(&self) -> PhysFrame {
+        self.p4
+    }
+
+    /// Backs the pages covering `start..start + len` with zeroed memory. Pages
+    /// that are already mapped keep their memory and gain `flags`, as far as
+    /// writing and executing go.
+    pub fn map_range(
+        &mut self,
+        start: VirtAddr,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), &'static str> {
+        unsafe { self.map_pages(start, len, flags) }
+    }
+
+    /// `map_range` for an address space that is shared, such as the one of
+    /// a running process.
+    ///
+    /// # Safety
+    /// Nothing else may change its mappings at the same time. If it is the
+    /// active page table, pages in the range that are already mapped must
+    /// not gain permissions: the TLB isn't flushed.
+    pub unsafe fn map_range_shared(
+        &self,
+        start: VirtAddr,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), &'static str> {
+        unsafe { self.map_pages(start, len, flags) }
+    }
+
+    // Callers make sure nobody else is changing the page tables
+    unsafe fn map_pages(
+        &self,
+        start: VirtAddr,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), &'static str> {
+        let end = start
+            .as_u64()
+            .checked_add(len)
+            .ok_or("address range overflows")?;
+        if len == 0 {
+            return Ok(());
+        }
+        if end > USER_SPACE_END {
+            return Err("address range outside user space");
+        }
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let mut mapper = self.mapper();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().expect("memory not initialized");
+
+        let pages = Page::range_inclusive(
+            Page::<Size4KiB>::containing_address(start),
+            Page::containing_address(VirtAddr::new(end - 1)),
+        );
+        for page in pages {
+            if let TranslateResult::Mapped { flags: old, .. } =
+                mapper.translate(page.start_address())
+            {
+                let mut merged = old | (flags & PageTableFlags::WRITABLE);
+                if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                    merged.remove(PageTableFlags::NO_EXECUTE);
+                }
+                // See `map_range_shared` for the active page table
+                unsafe { mapper.update_flags(page, merged) }
+                    .map_err(|_| "failed to update a user page")?
+                    .ignore();
+                continue;
+            }
+
+            let frame = frame_allocator.allocate_frame().ok_or("out of memory")?;
+            unsafe { zero_frame(frame) };
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+                .map_err(|_| "failed to map a user page")?
+                .ignore();
+        }
+        Ok(())
+    }
+
+    /// Copies `data` to `addr`, which must already be mapped.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), &'static str> {
+        let mapper = self.mapper();
+        let mut written = 0;
+        while written < data.len() {
+            let addr = addr + written as u64;
+            let phys = mapper
+                .translate_addr(addr)
+                .ok_or("writing to an unmapped user page")?;
+            let chunk = (4096 - u64::from(addr.page_offset()) as usize).min(data.len() - written);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    phys_to_virt(phys).as_mut_ptr::<u8>(),
+                    chunk,
+                );
+            }
+            written += chunk;
+        }
+        Ok(())
+    }
+
+    // The tables live in physical memory, not in `self`
+    fn mapper(&self) -> OffsetPageTable<'_> {
+        let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+        unsafe {
+            let table = &mut *(offset + self.p4.start_address().as_u64()).as_mut_ptr::<PageTable>();
+            OffsetPageTable::new(table, offset)
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    // Frees the user half: every page and page table in it belongs to us.
+    // Must not run while it is the active page table.
+    fn drop(&mut self) {
+        assert_ne!(Cr3::read().0, self.p4, "freeing the active address space");
+
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().expect("memory not initialized");
+        let p4 = unsafe { &*phys_to_virt(self.p4.start_address()).as_ptr::<PageTable>() };
+        for entry in p4.iter().take(USER_P4_ENTRIES) {
+            if let Ok(frame) = entry.frame() {
+                unsafe { free_table(frame, 3, frame_allocator) };
+            }
+        }
+        unsafe { frame_allocator.deallocate_frame(self.p4) };
+    }
+}
+
+// Frees a user page table of `level` (3 = P3, 1 = P1) and all it maps
+unsafe fn free_table(table: PhysFrame, level: u8, frame_allocator: &mut BootInfoFrameAllocator) {
+    let entries = unsafe { &*phys_to_virt(table.start_address()).as_ptr::<PageTable>() };
+    for entry in entries.iter() {
+        let Ok(frame) = entry.frame() else {
+            continue;
+        };
+        if level > 1 {
+            unsafe { free_table(frame, level - 1, frame_allocator) };
+        } else {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    }
+    unsafe { frame_allocator.deallocate_frame(table) };
+}
+
+fn allocate_zeroed_frame() -> Result<PhysFrame, &'static str> {
+    let frame = FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("memory not initialized")
+        .allocate_frame()
+        .ok_or("out of memory")?;
+    unsafe { zero_frame(frame) };
+    Ok(frame)
+}
+
+unsafe fn zero_frame(frame: PhysFrame) {
+    unsafe {
+        core::ptr::write_bytes(
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            0,
+            4096,
+        )
+    };
+}
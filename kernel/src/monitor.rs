@@ -0,0 +1,413 @@
+use crate::memory;
+use crate::symbols;
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use bootloader_api::info::MemoryRegionKind;
+use core::arch::global_asm;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::model_specific::Efer;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::{PhysAddr, VirtAddr};
+
+// A debug monitor for poking at the machine state from the shell.
+// Every number it takes or prints is hexadecimal.
+
+/// Bytes dumped by `x`/`xp` when no length is given, and the most they dump.
+const DEFAULT_DUMP_LEN: u64 = 0x80;
+const MAX_DUMP_LEN: u64 = 0x1000;
+
+// MSRs that `rdmsr` knows by name
+const NAMED_MSRS: &[(&str, u32)] = &[
+    ("apic_base", 0x1B),
+    ("efer", 0xC000_0080),
+    ("star", 0xC000_0081),
+    ("lstar", 0xC000_0082),
+    ("sfmask", 0xC000_0084),
+    ("fs_base", 0xC000_0100),
+    ("gs_base", 0xC000_0101),
+    ("kernel_gs_base", 0xC000_0102),
+    ("tsc_aux", 0xC000_0103),
+];
+
+global_asm!(include_str!("read_msr.asm"));
+
+unsafe extern "C" {
+    fn read_msr_checked(msr: u32, value: *mut u64) -> u64;
+    fn read_msr_access();
+    fn read_msr_fixup();
+}
+
+/// Where to resume after a #GP at `rip`, if it is the RDMSR of `rdmsr`.
+pub fn fixup(rip: u64) -> Option<u64> {
+    (rip == read_msr_access as *const () as u64).then_some(read_msr_fixup as *const () as u64)
+}
+
+/// Runs one monitor command and returns its output.
+pub fn execute(command: &str, args: &[&str]) -> Vec<String> {
+    let result = match command {
+        "help" => Ok(help()),
+        "x" => dump(args, false),
+        "xp" => dump(args, true),
+        "pt" => page_walk(args),
+        "gdt" => Ok(dump_gdt()),
+        "idt" => Ok(dump_idt()),
+        "tss" => Ok(dump_tss()),
+        "rdmsr" => read_msr(args),
+        "cr" => Ok(control_registers()),
+        "memmap" => Ok(memory_map()),
+        _ => Err("Unknown monitor command, try `help`"),
+    };
+
+    result.unwrap_or_else(|e| alloc::vec![e.to_string()])
+}
+
+fn help() -> Vec<String> {
+    [
+        "MONITOR COMMANDS (numbers in hex):",
+        "  x [addr] [len] - Hexdump virtual memory",
+        "  xp [addr] [len] - Hexdump physical memory",
+        "  pt [addr] - Walk the page tables for a virtual address",
+        "  gdt / idt / tss - Dump the descriptor tables and the TSS",
+        "  rdmsr [msr|name] - Read a model specific register",
+        "  cr - Show the control registers and EFER",
+        "  memmap - List the bootloader memory map",
+        "  exit - Leave the monitor (or press F12)",
+    ]
+    .iter()
+    .map(|line| line.to_string())
+    .collect()
+}
+
+fn parse_hex(arg: &str) -> Result<u64, &'static str> {
+    let digits = arg.strip_prefix("0x").unwrap_or(arg);
+    u64::from_str_radix(digits, 16).map_err(|_| "Invalid number, expected hex")
+}
+
+fn is_mapped(addr: u64) -> bool {
+    VirtAddr::try_new(addr)
+        .ok()
+        .and_then(memory::translate_addr)
+        .is_some()
+}
+
+/// Hexdump of `len` bytes at `addr`, 16 per line. Unmapped bytes show as `??`.
+fn dump(args: &[&str], physical: bool) -> Result<Vec<String>, &'static str> {
+    let addr = parse_hex(args.first().ok_or("Usage: x|xp [addr] [len]")?)?;
+    let len = match args.get(1) {
+        Some(len) => parse_hex(len)?.min(MAX_DUMP_LEN),
+        None => DEFAULT_DUMP_LEN,
+    };
+
+    // Physical memory is read through the bootloader's mapping of all of it
+    let base = if physical {
+        memory::phys_to_virt(PhysAddr::try_new(addr).map_err(|_| "Invalid physical address")?)
+            .as_u64()
+    } else {
+        addr
+    };
+
+    let mut output = Vec::new();
+    for line_offset in (0..len).step_by(16) {
+        let mut hex = String::new();
+        let mut ascii = String::new();
+
+        for i in line_offset..(line_offset + 16).min(len) {
+            let byte_addr = base.wrapping_add(i);
+            if is_mapped(byte_addr) {
+                let byte = unsafe { core::ptr::read_volatile(byte_addr as *const u8) };
+                hex.push_str(&format!("{:02x} ", byte));
+                ascii.push(if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                });
+            } else {
+                hex.push_str("?? ");
+                ascii.push('?');
+            }
+        }
+
+        output.push(format!(
+            "{:016x}  {:<48} {}",
+            addr.wrapping_add(line_offset),
+            hex,
+            ascii
+        ));
+    }
+    Ok(output)
+}
+
+/// Short flag names, e.g. `P W U NX`.
+fn page_flags(flags: PageTableFlags) -> String {
+    const NAMES: &[(PageTableFlags, &str)] = &[
+        (PageTableFlags::PRESENT, "P"),
+        (PageTableFlags::WRITABLE, "W"),
+        (PageTableFlags::USER_ACCESSIBLE, "U"),
+        (PageTableFlags::WRITE_THROUGH, "WT"),
+        (PageTableFlags::NO_CACHE, "NC"),
+        (PageTableFlags::ACCESSED, "A"),
+        (PageTableFlags::DIRTY, "D"),
+        (PageTableFlags::HUGE_PAGE, "H"),
+        (PageTableFlags::GLOBAL, "G"),
+        (PageTableFlags::NO_EXECUTE, "NX"),
+    ];
+
+    let names: Vec<&str> = NAMES
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, name)| *name)
+        .collect();
+    names.join(" ")
+}
+
+/// Prints the entry used at every level of the walk for `addr`.
+fn page_walk(args: &[&str]) -> Result<Vec<String>, &'static str> {
+    let addr = parse_hex(args.first().ok_or("Usage: pt [addr]")?)?;
+    let addr = VirtAddr::try_new(addr).map_err(|_| "Non-canonical address")?;
+
+    let (level_4_frame, _) = Cr3::read();
+    let mut output = alloc::vec![format!(
+        "Page walk for {:#x} (CR3 = {:#x})",
+        addr.as_u64(),
+        level_4_frame.start_address().as_u64()
+    )];
+
+    let levels = [
+        ("P4", addr.p4_index()),
+        ("P3", addr.p3_index()),
+        ("P2", addr.p2_index()),
+        ("P1", addr.p1_index()),
+    ];
+    let mut table_addr = level_4_frame.start_address();
+
+    for (level, (name, index)) in levels.iter().enumerate() {
+        let table = unsafe { &*memory::phys_to_virt(table_addr).as_ptr::<PageTable>() };
+        let entry = &table[*index];
+        let flags = entry.flags();
+
+        output.push(format!(
+            "  {}[{:>3}] -> {:#014x}  {}",
+            name,
+            u16::from(*index),
+            entry.addr().as_u64(),
+            page_flags(flags)
+        ));
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            output.push("  Not mapped".to_string());
+            return Ok(output);
+        }
+        // 1GiB pages end the walk at the P3 table, 2MiB pages at the P2 table
+        if flags.contains(PageTableFlags::HUGE_PAGE) && (level == 1 || level == 2) {
+            output.push(format!(
+                "  {} page",
+                if level == 1 { "1GiB" } else { "2MiB" }
+            ));
+            break;
+        }
+        table_addr = entry.addr();
+    }
+
+    if let Some(phys) = memory::translate_addr(addr) {
+        output.push(format!("  Physical address: {:#x}", phys.as_u64()));
+    }
+    Ok(output)
+}
+
+fn read_gdt_entry(base: u64, index: usize) -> u64 {
+    unsafe { core::ptr::read_unaligned((base as *const u64).add(index)) }
+}
+
+/// Base of a 16 byte system descriptor (TSS) whose low half is at `index`.
+fn system_descriptor_base(gdt_base: u64, index: usize) -> u64 {
+    let low = read_gdt_entry(gdt_base, index);
+    let high = read_gdt_entry(gdt_base, index + 1);
+    ((low >> 16) & 0xFF_FFFF) | (((low >> 56) & 0xFF) << 24) | ((high & 0xFFFF_FFFF) << 32)
+}
+
+fn dump_gdt() -> Vec<String> {
+    let gdtr = x86_64::instructions::tables::sgdt();
+    let base = gdtr.base.as_u64();
+    let count = (gdtr.limit as usize + 1) / 8;
+
+    let mut output = alloc::vec![format!(
+        "GDT at {:#x}, {} entries (limit {:#x})",
+        base, count, gdtr.limit
+    )];
+
+    let mut index = 0;
+    while index < count {
+        let raw = read_gdt_entry(base, index);
+        let access = (raw >> 40) & 0xFF;
+        let present = access & 0x80 != 0;
+        let dpl = (access >> 5) & 0b11;
+        // 64-bit TSS descriptors (available or busy) take two slots
+        let is_tss = access & 0x10 == 0 && matches!(access & 0xF, 0x9 | 0xB);
+
+        let description = if raw == 0 {
+            "null".to_string()
+        } else if !present {
+            "not present".to_string()
+        } else if is_tss {
+            let tss_base = system_descriptor_base(base, index);
+            let limit = (raw & 0xFFFF) | (((raw >> 48) & 0xF) << 16);
+            format!("TSS base={:#x} limit={:#x}", tss_base, limit)
+        } else if access & 0x8 != 0 {
+            let long_mode = raw & (1 << 53) != 0;
+            format!("code{} DPL{}", if long_mode { "64" } else { "32" }, dpl)
+        } else {
+            format!("data DPL{}", dpl)
+        };
+
+        output.push(format!(
+            "  [{:>2}] sel {:#06x}  {:016x}  {}",
+            index,
+            index * 8,
+            raw,
+            description
+        ));
+
+        index += if is_tss && present { 2 } else { 1 };
+    }
+    output
+}
+
+fn dump_idt() -> Vec<String> {
+    let idtr = x86_64::instructions::tables::sidt();
+    let base = idtr.base.as_u64();
+    let count = (idtr.limit as usize + 1) / 16;
+
+    let mut output = alloc::vec![format!(
+        "IDT at {:#x}, {} vectors, present ones:",
+        base, count
+    )];
+    output.push("  VEC  HANDLER             SEL     IST DPL TYPE".to_string());
+
+    for vector in 0..count {
+        let entry = unsafe { core::ptr::read_unaligned((base as *const [u8; 16]).add(vector)) };
+        let options = entry[5];
+        if options & 0x80 == 0 {
+            continue;
+        }
+
+        let handler = u16::from_le_bytes([entry[0], entry[1]]) as u64
+            | (u16::from_le_bytes([entry[6], entry[7]]) as u64) << 16
+            | (u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as u64) << 32;
+        let selector = u16::from_le_bytes([entry[2], entry[3]]);
+        let ist = entry[4] & 0b111;
+        let dpl = (options >> 5) & 0b11;
+        let kind = if options & 0xF == 0xF { "trap" } else { "intr" };
+        let name = symbols::lookup(handler).map_or("", |symbol| symbol.name);
+
+        output.push(format!(
+            "  {:>3}  {:#018x}  {:#06x}  {:>3} {:>3} {}  {}",
+            vector, handler, selector, ist, dpl, kind, name
+        ));
+    }
+    output
+}
+
+fn dump_tss() -> Vec<String> {
+    let selector: u16;
+    unsafe {
+        core::arch::asm!("str {0:x}", out(reg) selector, options(nomem, nostack, preserves_flags));
+    }
+    if selector == 0 {
+        return alloc::vec!["No TSS loaded".to_string()];
+    }
+
+    let gdt_base = x86_64::instructions::tables::sgdt().base.as_u64();
+    let tss_addr = system_descriptor_base(gdt_base, (selector >> 3) as usize);
+    let tss = unsafe { core::ptr::read_unaligned(tss_addr as *const TaskStateSegment) };
+
+    let mut output = alloc::vec![format!(
+        "TSS at {:#x} (selector {:#06x})",
+        tss_addr, selector
+    )];
+    let privilege_stacks = tss.privilege_stack_table;
+    for (i, stack) in privilege_stacks.iter().enumerate() {
+        output.push(format!("  RSP{}: {:#x}", i, stack.as_u64()));
+    }
+    let interrupt_stacks = tss.interrupt_stack_table;
+    for (i, stack) in interrupt_stacks.iter().enumerate() {
+        // The IDT numbers these from 1
+        output.push(format!("  IST{}: {:#x}", i + 1, stack.as_u64()));
+    }
+    let iomap_base = tss.iomap_base;
+    output.push(format!("  I/O map base: {:#x}", iomap_base));
+    output
+}
+
+fn read_msr(args: &[&str]) -> Result<Vec<String>, &'static str> {
+    let Some(arg) = args.first() else {
+        let mut output = alloc::vec!["Usage: rdmsr [msr|name], known names:".to_string()];
+        for (name, msr) in NAMED_MSRS {
+            output.push(format!("  {:<15} {:#x}", name, msr));
+        }
+        return Ok(output);
+    };
+
+    let msr = match NAMED_MSRS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(arg))
+    {
+        Some((_, msr)) => *msr,
+        None => u32::try_from(parse_hex(arg)?).map_err(|_| "MSR numbers are 32 bit")?,
+    };
+
+    // An MSR the CPU doesn't implement raises #GP, which the fixup catches
+    let mut value = 0;
+    if unsafe { read_msr_checked(msr, &mut value) } != 0 {
+        return Err("The CPU doesn't implement that MSR");
+    }
+    Ok(alloc::vec![format!("MSR {:#x} = {:#018x}", msr, value)])
+}
+
+fn control_registers() -> Vec<String> {
+    let (cr3_frame, cr3_flags) = Cr3::read_raw();
+    alloc::vec![
+        format!("CR0  = {:#018x}  {:?}", Cr0::read_raw(), Cr0::read()),
+        format!("CR2  = {:#018x}", Cr2::read_raw()),
+        format!(
+            "CR3  = {:#018x}  flags {:#x}",
+            cr3_frame.start_address().as_u64(),
+            cr3_flags
+        ),
+        format!("CR4  = {:#018x}  {:?}", Cr4::read_raw(), Cr4::read()),
+        format!("EFER = {:#018x}  {:?}", Efer::read_raw(), Efer::read()),
+    ]
+}
+
+fn memory_map() -> Vec<String> {
+    let frame_allocator = memory::FRAME_ALLOCATOR.lock();
+    let Some(frame_allocator) = frame_allocator.as_ref() else {
+        return alloc::vec!["Memory not initialized".to_string()];
+    };
+
+    let mut output =
+        alloc::vec!["START             END               SIZE (KiB)  KIND".to_string()];
+    let mut usable = 0;
+    for region in frame_allocator.memory_map().iter() {
+        let size = region.end - region.start;
+        if region.kind == MemoryRegionKind::Usable {
+            usable += size;
+        }
+        output.push(format!(
+            "{:016x}  {:016x}  {:>10}  {:?}",
+            region.start,
+            region.end,
+            size / 1024,
+            region.kind
+        ));
+    }
+    output.push(format!(
+        "Usable: {} MiB, {} frames handed out",
+        usable / (1024 * 1024),
+        frame_allocator.allocated_frames()
+    ));
+    output
+}
@@ -1,9 +1,190 @@
-use crate::serial::{QemuExitCode, exit_qemu};
-use crate::serial_println;
+use crate::backtrace::{self, Backtrace};
+use crate::framebuffer::WRITER;
+use crate::percpu::MAX_CPUS;
+use crate::serial::{QemuExitCode, SERIAL1, exit_qemu};
+use crate::time::{Duration, Instant};
+use crate::{apic, percpu, serial_println};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::{hlt, interrupts};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+// Set by the first panic; a panic while reporting one only goes to serial
+static PANICKING: AtomicBool = AtomicBool::new(false);
+// Other CPUs stopped by the panic NMI
+static HALTED_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+// How long the panicking CPU waits for the others to stop
+const HALT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Whether a panic is being (or has been) reported.
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
+}
+
+/// Called by the NMI handler: stops this CPU for good if another one panicked.
+pub fn halt_if_panicking() {
+    if is_panicking() {
+        HALTED_CPUS.fetch_add(1, Ordering::SeqCst);
+        halt();
+    }
+}
+
+/// Register state at the time of the panic.
+struct Registers {
+    rsp: u64,
+    rbp: u64,
+    rflags: u64,
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+}
+
+impl Registers {
+    #[inline(always)]
+    fn capture() -> Self {
+        let rsp: u64;
+        unsafe {
+            core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+        }
+        Registers {
+            rsp,
+            rbp: backtrace::frame_pointer(),
+            rflags: x86_64::registers::rflags::read_raw(),
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read_raw(),
+            cr3: Cr3::read_raw().0.start_address().as_u64(),
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "  RSP={:#018x}  RBP={:#018x}  RFLAGS={:#010x}",
+            self.rsp, self.rbp, self.rflags
+        )?;
+        writeln!(
+            f,
+            "  CR0={:#010x}  CR2={:#018x}  CR3={:#018x}  CR4={:#010x}",
+            self.cr0, self.cr2, self.cr3, self.cr4
+        )
+    }
+}
 
 #[panic_handler]
 #[cfg(not(test))]
-pub fn panic(info: &core::panic::PanicInfo) -> ! {
+pub fn panic(info: &PanicInfo) -> ! {
+    interrupts::disable();
+
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        // Don't try the screen again, it may be what failed
+        unsafe { force_unlock_serial() };
+        serial_println!("PANIC while panicking: {}", info);
+        halt();
+    }
+
+    let registers = Registers::capture();
+    let backtrace = Backtrace::capture();
+
+    // The other CPUs may be holding WRITER or SERIAL1, and would keep using
+    // them once they are forced open below
+    halt_other_cpus();
+
+    // The panicking code may have been printing to serial
+    unsafe { force_unlock_serial() };
     serial_println!("PANIC: {}", info);
+    serial_println!("Registers:\n{}", registers);
+    serial_println!("Backtrace:\n{}", backtrace);
+
+    paint_panic_screen(info, &registers, &backtrace);
+
     exit_qemu(QemuExitCode::Failed);
 }
+
+// Sends every other CPU an NMI, which gets through `cli`, and waits a moment
+// for them to halt
+fn halt_other_cpus() {
+    let Some(this_cpu) = percpu::try_current() else {
+        return;
+    };
+    if !apic::is_initialized() {
+        return;
+    }
+
+    let mut others = 0;
+    for cpu in (0..MAX_CPUS).filter_map(percpu::get) {
+        if cpu.cpu_id != this_cpu.cpu_id {
+            apic::send_nmi(cpu.apic_id);
+            others += 1;
+        }
+    }
+
+    let sent = Instant::now();
+    while HALTED_CPUS.load(Ordering::SeqCst) < others && sent.elapsed() < HALT_TIMEOUT {
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn force_unlock_serial() {
+    if SERIAL1.is_locked() {
+        unsafe { SERIAL1.force_unlock() };
+    }
+}
+
+fn halt() -> ! {
+    loop {
+        hlt();
+    }
+}
+
+// Panic screen colors
+const BACKGROUND: (u8, u8, u8) = (0x80, 0x00, 0x00);
+const TITLE: (u8, u8, u8) = (0xFF, 0xD0, 0x40);
+const TEXT: (u8, u8, u8) = (0xFF, 0xFF, 0xFF);
+const MARGIN: usize = 16;
+
+/// Paints the report over the whole framebuffer and presents it.
+fn paint_panic_screen(info: &PanicInfo, registers: &Registers, backtrace: &Backtrace) {
+    // A panic inside a `WRITER.lock()` (e.g. in `demo::bouncing_box`) still holds the
+    // lock, and this CPU will never release it. Interrupts are off, so taking it over
+    // can't race with anything else on this CPU.
+    if WRITER.is_locked() {
+        unsafe { WRITER.force_unlock() };
+    }
+
+    let mut writer = WRITER.lock();
+    let Some(writer) = writer.as_mut() else {
+        return;
+    };
+
+    let (width, height) = (writer.width(), writer.height());
+    writer.set_color(BACKGROUND.0, BACKGROUND.1, BACKGROUND.2);
+    writer.draw_rect(0, 0, width, height, false);
+
+    writer.set_cursor(MARGIN, MARGIN);
+    writer.set_scale(2);
+    writer.set_color(TITLE.0, TITLE.1, TITLE.2);
+    let _ = write!(writer, "KERNEL PANIC");
+
+    writer.set_scale(1);
+    writer.set_color(TEXT.0, TEXT.1, TEXT.2);
+    writer.set_cursor(MARGIN, MARGIN * 3);
+    let _ = writeln!(writer, "{}", info.message());
+    if let Some(location) = info.location() {
+        let _ = writeln!(writer, "at {}", location);
+    }
+    if let Some(cpu) = percpu::try_current() {
+        let _ = writeln!(writer, "on CPU {}", cpu.cpu_id);
+    }
+
+    let _ = writeln!(writer, "\nRegisters:\n{}", registers);
+    let _ = write!(writer, "Backtrace:\n{}", backtrace);
+    let _ = write!(writer, "\nSystem halted.");
+
+    writer.present();
+}
@@ -0,0 +1,215 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use core::arch::x86_64::__cpuid;
+use core::mem::offset_of;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use x86_64::instructions::segmentation::GS;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+pub const MAX_CPUS: usize = 64;
+/// Stack `syscall_dispatcher` switches to, one per CPU.
+pub const SYSCALL_STACK_SIZE: usize = 4096 * 4;
+
+/// Value of `current_task` / `current_process` when nothing is running.
+pub const NONE: u64 = u64::MAX;
+
+/// Per-CPU counters, readable from any CPU.
+#[derive(Debug, Default)]
+pub struct CpuStats {
+    pub interrupts: AtomicU64,
+    pub syscalls: AtomicU64,
+    pub task_polls: AtomicU64,
+    pub context_switches: AtomicU64,
+}
+
+/// Data private to one CPU, reached through the GS base.
+///
+/// Kernel code always runs with `GS_BASE` pointing at this struct, and user
+/// code with 0 (the base of every segment it can load). Whatever isn't in
+/// use sits in `KERNEL_GS_BASE`: every way into the kernel from ring 3 (the
+/// syscall path, and interrupt and exception handlers through `KernelGs`)
+/// does a `swapgs` on the way in and out.
+#[repr(C)]
+pub struct PerCpu {
+    pub kernel_stack_top: AtomicU64, // Offset 0, loaded by syscall_dispatcher
+    pub user_stack_scratch: AtomicU64, // Offset 8, saved by syscall_dispatcher
+    self_ptr: *const PerCpu,         // Offset 16, read by `current`
+    pub cpu_id: usize,
+    pub apic_id: u8,
+    pub current_task: AtomicU64,
+    pub current_process: AtomicU64,
+    pub scratch: [AtomicU64; 4],
+    pub stats: CpuStats,
+    // What the last IRQ on this CPU interrupted, for the watchdog
+    pub interrupted_rip: AtomicU64,
+    pub interrupted_rbp: AtomicU64,
+    // TSC when the executor started its current poll, 0 while idle
+    pub poll_started: AtomicU64,
+    // TSC by which the current poll should return, see `task::consume_budget`
+    pub poll_deadline: AtomicU64,
+    // Advanced by every timer tick this CPU handles
+    pub heartbeat: AtomicU64,
+}
+
+// syscall_asm.asm hardcodes these
+const _: () = assert!(offset_of!(PerCpu, kernel_stack_top) == 0);
+const _: () = assert!(offset_of!(PerCpu, user_stack_scratch) == 8);
+const SELF_PTR_OFFSET: usize = offset_of!(PerCpu, self_ptr);
+
+// Each area is only ever written by its own CPU; the atomics make the
+// cross-CPU reads (statistics, `cpus`) sound.
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    const fn new(cpu_id: usize, apic_id: u8) -> Self {
+        PerCpu {
+            kernel_stack_top: AtomicU64::new(0),
+            user_stack_scratch: AtomicU64::new(0),
+            self_ptr: ptr::null(),
+            cpu_id,
+            apic_id,
+            current_task: AtomicU64::new(NONE),
+            current_process: AtomicU64::new(NONE),
+            scratch: [const { AtomicU64::new(0) }; 4],
+            stats: CpuStats {
+                interrupts: AtomicU64::new(0),
+                syscalls: AtomicU64::new(0),
+                task_polls: AtomicU64::new(0),
+                context_switches: AtomicU64::new(0),
+            },
+            interrupted_rip: AtomicU64::new(0),
+            interrupted_rbp: AtomicU64::new(0),
+            poll_started: AtomicU64::new(0),
+            poll_deadline: AtomicU64::new(0),
+            heartbeat: AtomicU64::new(0),
+        }
+    }
+}
+
+// The BSP sets up its area before the heap exists
+static mut BSP_AREA: PerCpu = PerCpu::new(0, 0);
+static mut BSP_SYSCALL_STACK: [u8; SYSCALL_STACK_SIZE] = [0; SYSCALL_STACK_SIZE];
+
+static AREAS: [AtomicPtr<PerCpu>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+/// Installs the bootstrap CPU's area. Must run after `gdt::init`, which
+/// reloads GS.
+pub fn init_bsp() {
+    let area = &raw mut BSP_AREA;
+    unsafe {
+        (*area).apic_id = initial_apic_id();
+        install(0, area, &raw mut BSP_SYSCALL_STACK as *mut u8);
+    }
+}
+
+/// Allocates and installs the area and syscall stack of an application
+/// processor.
+pub fn init_ap(cpu_id: usize) {
+    let area = Box::into_raw(Box::new(PerCpu::new(cpu_id, initial_apic_id())));
+    let stack = Box::leak(vec![0u8; SYSCALL_STACK_SIZE].into_boxed_slice());
+    unsafe { install(cpu_id, area, stack.as_mut_ptr()) };
+}
+
+// `stack` is the bottom of the CPU's `SYSCALL_STACK_SIZE` syscall stack
+unsafe fn install(cpu_id: usize, area: *mut PerCpu, stack: *mut u8) {
+    assert!(cpu_id < MAX_CPUS, "too many CPUs");
+
+    unsafe {
+        (*area).self_ptr = area;
+        let stack_top = stack.add(SYSCALL_STACK_SIZE) as u64;
+        (*area).kernel_stack_top.store(stack_top, Ordering::Relaxed);
+    }
+    AREAS[cpu_id].store(area, Ordering::Release);
+
+    GsBase::write(VirtAddr::from_ptr(area));
+    // The user's, for the first `swapgs` into ring 3
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+/// Switches to the kernel's GS base for an interrupt or exception handler,
+/// and back to the user's when dropped, right before the handler returns.
+/// Must be created before the handler touches the per-CPU area.
+pub struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    /// For an interrupt or exception that came from ring 3.
+    pub fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        Self::swap_if(stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3)
+    }
+
+    /// For the NMI and double fault, which can also arrive in the kernel
+    /// before the syscall path's `swapgs`: goes by the GS base itself.
+    pub fn enter_paranoid() -> Self {
+        Self::swap_if(GsBase::read().is_null())
+    }
+
+    fn swap_if(user: bool) -> Self {
+        if user {
+            unsafe { GS::swap() };
+        }
+        KernelGs { swapped: user }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+/// The calling CPU's area.
+#[inline(always)]
+pub fn current() -> &'static PerCpu {
+    let area: *const PerCpu;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, gs:[{offset}]",
+            out(reg) area,
+            offset = const SELF_PTR_OFFSET,
+            options(nostack, readonly, preserves_flags)
+        );
+        &*area
+    }
+}
+
+/// The calling CPU's area, or `None` if it hasn't been installed yet.
+pub fn try_current() -> Option<&'static PerCpu> {
+    if GsBase::read().is_null() {
+        return None;
+    }
+    Some(current())
+}
+
+/// Area of CPU `cpu_id`, if that CPU has come up.
+pub fn get(cpu_id: usize) -> Option<&'static PerCpu> {
+    let area = AREAS.get(cpu_id)?.load(Ordering::Acquire);
+    unsafe { area.as_ref() }
+}
+
+fn initial_apic_id() -> u8 {
+    // Leaf 1 EBX[31:24] = Initial APIC ID
+    (unsafe { __cpuid(1) }.ebx >> 24) as u8
+}
+
+/// Access the calling CPU's `PerCpu` area, or one of its fields.
+///
+/// ```ignore
+/// percpu!(stats).interrupts.fetch_add(1, Ordering::Relaxed);
+/// let cpu = percpu!().cpu_id;
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    () => {
+        $crate::percpu::current()
+    };
+    ($field:ident) => {
+        &$crate::percpu::current().$field
+    };
+}
@@ -0,0 +1,347 @@
+use crate::elf::Program;
+use crate::file::{self, FdTable};
+use crate::memory::AddressSpace;
+use crate::syscall::{self, SyscallFrame};
+use crate::task::sync::Notify;
+use crate::thread::{self, ThreadId};
+use crate::user;
+use crate::{percpu, percpu::NONE};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use spin::{Mutex, Once};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+// A process is a user program in its own address space, run by one kernel
+// thread. The thread's kernel stack is the process's kernel stack: syscalls
+// and interrupts from ring 3 land on it. After `exit` the process stays in
+// the table as a zombie until `wait` collects its exit status.
+
+/// Exit status of a process killed for a CPU exception.
+pub const EXIT_FAULT: i64 = -1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        // 0 is the kernel
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for Pid {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Pid).map_err(|_| "invalid pid")
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Runnable, in ring 3 or in a syscall.
+    Running,
+    /// Waiting in a syscall, for input or a timeout.
+    Blocked,
+    /// Exited, waiting for `wait`.
+    Zombie,
+}
+
+impl State {
+    fn from_u8(value: u8) -> State {
+        match value {
+            0 => State::Running,
+            1 => State::Blocked,
+            _ => State::Zombie,
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            State::Running => "running",
+            State::Blocked => "blocked",
+            State::Zombie => "zombie",
+        };
+        f.pad(name)
+    }
+}
+
+/// User registers as of the last entry into the kernel through `syscall`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserContext {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    pub rax: u64,
+    pub rbx: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rbp: u64,
+}
+
+pub struct Process {
+    pid: Pid,
+    parent: Option<Pid>,
+    name: String,
+    state: AtomicU8,
+    // Released on exit; the thread keeps its own reference until it is gone
+    address_space: Mutex<Option<Arc<AddressSpace>>>,
+    context: Mutex<UserContext>,
+    files: Mutex<FdTable>,
+    // See `brk`
+    heap_end: Mutex<u64>,
+    thread: Once<ThreadId>,
+    exit_status: Mutex<Option<i64>>,
+    exited: Notify,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        self.parent
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> State {
+        State::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    /// The thread running it, whose stack is its kernel stack.
+    pub fn thread(&self) -> Option<ThreadId> {
+        self.thread.get().copied()
+    }
+
+    pub fn context(&self) -> UserContext {
+        *self.context.lock()
+    }
+
+    /// Its open files, by descriptor.
+    pub fn files(&self) -> &Mutex<FdTable> {
+        &self.files
+    }
+
+    /// Set once it has exited.
+    pub fn exit_status(&self) -> Option<i64> {
+        *self.exit_status.lock()
+    }
+}
+
+static PROCESSES: Mutex<BTreeMap<Pid, Arc<Process>>> = Mutex::new(BTreeMap::new());
+
+/// Gives `program` a stack holding `args` (the first being its name, by
+/// convention) and starts it in ring 3 as a new process.
+pub fn spawn(
+    name: &str,
+    parent: Option<Pid>,
+    program: Program,
+    args: &[&str],
+) -> Result<Arc<Process>, &'static str> {
+    let Program {
+        mut address_space,
+        entry,
+    } = program;
+    address_space.map_range(
+        VirtAddr::new(user::STACK_TOP - user::STACK_SIZE),
+        user::STACK_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )?;
+    let stack_pointer = push_args(&mut address_space, args)?;
+    let address_space = Arc::new(address_space);
+
+    let process = Arc::new(Process {
+        pid: Pid::new(),
+        parent,
+        name: String::from(name),
+        state: AtomicU8::new(State::Running as u8),
+        address_space: Mutex::new(Some(address_space.clone())),
+        context: Mutex::new(UserContext {
+            rip: entry,
+            rsp: stack_pointer,
+            ..UserContext::default()
+        }),
+        files: Mutex::new(FdTable::new()),
+        heap_end: Mutex::new(user::HEAP_START),
+        thread: Once::new(),
+        exit_status: Mutex::new(None),
+        exited: Notify::new(),
+    });
+    // In the table before its thread can look for it
+    PROCESSES.lock().insert(process.pid, process.clone());
+
+    let thread = thread::spawn_user(name, process.pid.0, address_space, move || unsafe {
+        syscall::enter_userspace(entry, stack_pointer)
+    });
+    process.thread.call_once(|| thread);
+    Ok(process)
+}
+
+// Lays out `args` at the top of the stack for `_start`, as Linux does:
+// argc, the argv pointers and a NULL, then an empty envp and auxv, with the
+// strings above. Returns the stack pointer.
+fn push_args(address_space: &mut AddressSpace, args: &[&str]) -> Result<u64, &'static str> {
+    let strings_size: u64 = args.iter().map(|arg| arg.len() as u64 + 1).sum();
+    // argc, argv, NULL, envp's NULL and an AT_NULL auxv entry (two words)
+    let table_size = (args.len() as u64 + 5) * 8;
+    if strings_size + table_size + 16 > user::STACK_SIZE / 2 {
+        return Err("arguments too long");
+    }
+
+    let mut table = Vec::with_capacity(args.len() + 5);
+    table.push(args.len() as u64);
+    let mut string = user::STACK_TOP;
+    for arg in args {
+        // Followed by a NUL: the stack is zeroed
+        string -= arg.len() as u64 + 1;
+        address_space.write(VirtAddr::new(string), arg.as_bytes())?;
+        table.push(string);
+    }
+    table.extend([0; 4]);
+
+    let stack_pointer = (string - table_size) & !0xF;
+    let bytes: Vec<u8> = table.iter().flat_map(|word| word.to_le_bytes()).collect();
+    address_space.write(VirtAddr::new(stack_pointer), &bytes)?;
+    Ok(stack_pointer)
+}
+
+/// The process whose thread is running.
+pub fn current() -> Option<Arc<Process>> {
+    let pid = percpu!(current_process).load(Ordering::Relaxed);
+    if pid == NONE {
+        return None;
+    }
+    get(Pid(pid))
+}
+
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+/// Every process in the table, zombies included, by PID.
+pub fn processes() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().cloned().collect()
+}
+
+/// Ends the calling process with `status`.
+pub fn exit(status: i64) -> ! {
+    if let Some(process) = current() {
+        log::debug!(
+            "process {} ({}) exited with {}",
+            process.pid,
+            process.name,
+            status
+        );
+        let files = process.files.lock().take_all();
+        for file in files {
+            let _ = file::close(file);
+        }
+        *process.exit_status.lock() = Some(status);
+        process.address_space.lock().take();
+        process.set_state(State::Zombie);
+        process.exited.notify_one();
+    }
+    thread::exit();
+}
+
+/// Waits for process `pid` to exit, removes it from the table and returns
+/// its exit status.
+pub async fn wait(pid: Pid) -> Result<i64, &'static str> {
+    let process = get(pid).ok_or("no such process")?;
+    loop {
+        if let Some(status) = process.exit_status() {
+            PROCESSES.lock().remove(&pid);
+            // Pass the wakeup on to anyone else waiting for it
+            process.exited.notify_one();
+            return Ok(status);
+        }
+        process.exited.notified().await;
+    }
+}
+
+/// Moves the end of the calling process's heap, which starts at
+/// `user::HEAP_START`, up to `end` and backs it with zeroed memory. The heap
+/// never shrinks. Returns the new end.
+pub fn brk(end: u64) -> Result<u64, &'static str> {
+    let process = current().ok_or("not a process")?;
+    let mut heap_end = process.heap_end.lock();
+    if end <= *heap_end {
+        return Ok(*heap_end);
+    }
+    if end > user::HEAP_START + user::HEAP_MAX_SIZE {
+        return Err("heap too large");
+    }
+
+    let address_space = process
+        .address_space
+        .lock()
+        .clone()
+        .ok_or("process has exited")?;
+    // Pages below it are mapped already
+    let mapped = heap_end.next_multiple_of(4096);
+    // The lock keeps others out, and the new pages weren't mapped before
+    unsafe {
+        address_space.map_range_shared(
+            VirtAddr::new(mapped),
+            end.saturating_sub(mapped),
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )?;
+    }
+    *heap_end = end;
+    Ok(end)
+}
+
+/// Runs `f` with the calling process marked as blocked.
+pub fn blocked<T>(f: impl FnOnce() -> T) -> T {
+    let process = current();
+    if let Some(process) = &process {
+        process.set_state(State::Blocked);
+    }
+    let result = f();
+    if let Some(process) = &process {
+        process.set_state(State::Running);
+    }
+    result
+}
+
+/// Records the user registers of a syscall as the calling process's context.
+pub fn save_context(frame: &SyscallFrame) {
+    if let Some(process) = current() {
+        *process.context.lock() = UserContext {
+            rip: frame.rip,
+            rsp: frame.rsp,
+            rflags: frame.rflags,
+            rax: frame.rax,
+            rbx: frame.rbx,
+            rdi: frame.rdi,
+            rsi: frame.rsi,
+            rdx: frame.rdx,
+            rbp: frame.rbp,
+        };
+    }
+}
@@ -0,0 +1,20 @@
+.global read_msr_checked
+.global read_msr_access
+.global read_msr_fixup
+
+// fn read_msr_checked(msr: u32, value: *mut u64) -> u64
+// Reads an MSR the CPU may not implement. Returns 0 and stores the value,
+// or returns 1 if RDMSR raised #GP: `general_protection_fault_handler`
+// resumes at the fixup (see `monitor::fixup`).
+read_msr_checked:
+    mov ecx, edi
+read_msr_access:
+    rdmsr
+    shl rdx, 32
+    or rax, rdx
+    mov [rsi], rax
+    xor eax, eax
+    ret
+read_msr_fixup:
+    mov eax, 1
+    ret
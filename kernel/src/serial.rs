@@ -44,6 +44,15 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// Prints to COM1 without taking `SERIAL1`, for code that may have interrupted
+/// its holder (NMI handlers). The output can interleave with other prints.
+pub fn _print_unlocked(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    let mut port = unsafe { SerialPort::new(0x3F8) };
+    let _ = port.write_fmt(args);
+}
+
 #[macro_export]
 macro_rules! serial_print {
     ($($arg:tt)*) => {
@@ -1,18 +1,36 @@
-use crate::framebuffer::WRITER;
+use crate::elf::{self, Program};
+use crate::framebuffer;
 use crate::fs;
 use crate::fs::FILESYSTEM;
+use crate::logger::{self, Sink};
+use crate::process;
+use crate::task::executor::{self, TaskSnapshot};
 use crate::task::keyboard::ScancodeStream;
+use crate::task::{self, JoinHandle, Priority, TaskId, timer};
+use crate::time::{self, Duration, Instant};
+use crate::{gdb, interrupts, monitor, percpu, smp, thread, user};
 use crate::{print, println};
 
 use alloc::{
+    collections::BTreeMap,
+    format,
     string::{String, ToString},
+    vec,
     vec::Vec,
 };
+use core::pin::pin;
+use core::str::FromStr;
+use core::sync::atomic::Ordering;
+use futures_util::future::{self, Either};
 use futures_util::stream::StreamExt;
+use log::LevelFilter;
 use pc_keyboard::{DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1, layouts};
 
+type ShellKeyboard = Keyboard<layouts::Us104Key, ScancodeSet1>;
+
 pub async fn runshell() {
     let prompt = "samux> ";
+    let monitor_prompt = "monitor> ";
 
     let mut scancode_stream = ScancodeStream::new();
     let mut keyboard = Keyboard::new(
@@ -21,8 +39,12 @@ pub async fn runshell() {
         HandleControl::Ignore,
     );
 
+    // The monitor takes over the prompt until `exit` or F12
+    let mut in_monitor = false;
+    let mut jobs = Jobs::new();
+
     loop {
-        print!("{}", prompt);
+        print!("{}", if in_monitor { monitor_prompt } else { prompt });
 
         let mut input_buffer: String = String::new();
 
@@ -52,6 +74,12 @@ pub async fn runshell() {
                                 print!("\x08 \x08");
                             }
                         }
+                        DecodedKey::RawKey(KeyCode::F12) => {
+                            in_monitor = !in_monitor;
+                            input_buffer.clear();
+                            println!();
+                            break;
+                        }
                         DecodedKey::RawKey(_) => {}
                     }
                 }
@@ -61,7 +89,28 @@ pub async fn runshell() {
         let mut parts = input_buffer.split_whitespace();
         if let Some(command) = parts.next() {
             let args: Vec<&str> = parts.collect();
-            let output = execute_command(command, &args);
+            let output = if in_monitor {
+                if command == "exit" {
+                    in_monitor = false;
+                    Vec::new()
+                } else {
+                    monitor::execute(command, &args)
+                }
+            } else if command == "monitor" && args.is_empty() {
+                in_monitor = true;
+                vec!["Debug monitor, `help` lists its commands".to_string()]
+            } else if command == "top" {
+                top(&mut scancode_stream).await;
+                Vec::new()
+            } else if command == "usertest" || command == "run" {
+                run_program(command, &args, &mut scancode_stream, &mut keyboard).await
+            } else if let Some((&"&", args)) = args.split_last() {
+                jobs.start(command, args)
+            } else if let Some(output) = jobs.execute(command, &args).await {
+                output
+            } else {
+                execute_command(command, &args).await
+            };
             for line in output {
                 println!("{}", line);
             }
@@ -69,7 +118,225 @@ pub async fn runshell() {
     }
 }
 
-fn execute_command(command: &str, args: &[&str]) -> Vec<String> {
+/// `usertest` runs the built-in ring 3 test program, `run <file>` an ELF
+/// executable from the disk. Either runs in the foreground, getting what is
+/// typed, until it exits.
+async fn run_program(
+    command: &str,
+    args: &[&str],
+    scancodes: &mut ScancodeStream,
+    keyboard: &mut ShellKeyboard,
+) -> Vec<String> {
+    let (name, program, args) = if command == "usertest" {
+        (
+            "usertest",
+            user::load_flat(user::test_program()),
+            &["usertest"][..],
+        )
+    } else {
+        let Some(&filename) = args.first() else {
+            return vec!["Usage: run <file> [args...]".to_string()];
+        };
+        (filename, load_program(filename).await, args)
+    };
+    let pid = match program.and_then(|program| process::spawn(name, None, program, args)) {
+        Ok(process) => process.pid(),
+        Err(e) => return vec![format!("{}: {}", name, e)],
+    };
+    user::clear_input();
+
+    let mut exited = pin!(process::wait(pid));
+    loop {
+        match future::select(exited.as_mut(), scancodes.next()).await {
+            Either::Left((Ok(status), _)) => {
+                return vec![format!(
+                    "{} (pid {}) exited with code {}",
+                    name, pid, status
+                )];
+            }
+            Either::Left((Err(e), _)) => return vec![format!("{}: {}", name, e)],
+            Either::Right((Some(scancode), _)) => {
+                if let Ok(Some(key_event)) = keyboard.add_byte(scancode)
+                    && let Some(DecodedKey::Unicode(character)) =
+                        keyboard.process_keyevent(key_event)
+                    && character.is_ascii()
+                {
+                    user::push_input(character as u8);
+                }
+            }
+            Either::Right((None, _)) => {}
+        }
+    }
+}
+
+async fn load_program(filename: &str) -> Result<Program, &'static str> {
+    let file = {
+        let mut fs_lock = FILESYSTEM.lock().await;
+        let fs = fs_lock.as_mut().ok_or("Filesystem not initialized!")?;
+        fs.read_file_async(filename).await?
+    };
+    elf::load(&file)
+}
+
+// How often `top` redraws
+const TOP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Redraws the task list every second, with each task's share of the CPU
+/// since the last redraw, until a key is pressed.
+async fn top(scancodes: &mut ScancodeStream) {
+    let mut previous: BTreeMap<TaskId, Duration> = BTreeMap::new();
+    let mut last = Instant::now();
+
+    loop {
+        let tasks = executor::snapshot();
+        let now = Instant::now();
+        let interval = now.duration_since(last);
+
+        let mut usage: Vec<(u64, &TaskSnapshot)> = tasks
+            .iter()
+            .filter(|task| task.state != executor::TaskState::Done)
+            .map(|task| {
+                let before = previous.get(&task.id).copied().unwrap_or_default();
+                (
+                    per_mille(task.poll_time.saturating_sub(before), interval),
+                    task,
+                )
+            })
+            .collect();
+        usage.sort_by(|a, b| b.0.cmp(&a.0));
+
+        framebuffer::clear_console();
+        let uptime = time::uptime();
+        println!(
+            "top - up {}.{:01}s, {} tasks, any key quits",
+            uptime.as_secs(),
+            uptime.subsec_millis() / 100,
+            usage.len()
+        );
+        println!("  ID  STATE      CPU%     POLLS  NAME");
+        for (per_mille, task) in &usage {
+            println!(
+                "{:>4}  {:<8}  {:>3}.{}  {:>8}  {}",
+                task.id.as_u64(),
+                task.state,
+                per_mille / 10,
+                per_mille % 10,
+                task.polls,
+                task.name
+            );
+        }
+
+        previous = tasks.iter().map(|task| (task.id, task.poll_time)).collect();
+        last = now;
+
+        match future::select(timer::sleep(TOP_INTERVAL), scancodes.next()).await {
+            Either::Left(_) => {}
+            Either::Right(_) => break,
+        }
+    }
+}
+
+// `part` of `whole` in tenths of a percent
+fn per_mille(part: Duration, whole: Duration) -> u64 {
+    if whole.is_zero() {
+        return 0;
+    }
+    (part.as_nanos() * 1000 / whole.as_nanos()).min(1000) as u64
+}
+
+// A command started in the background with a trailing `&`
+struct Job {
+    number: usize,
+    command: String,
+    handle: JoinHandle<Vec<String>>,
+}
+
+struct Jobs {
+    jobs: Vec<Job>,
+    next_number: usize,
+}
+
+impl Jobs {
+    fn new() -> Self {
+        Jobs {
+            jobs: Vec::new(),
+            next_number: 1,
+        }
+    }
+
+    fn start(&mut self, command: &str, args: &[&str]) -> Vec<String> {
+        let command = command.to_string();
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let line = format!("{} {}", command, args.join(" "));
+        let line = line.trim_end().to_string();
+
+        // Jobs mustn't slow down typing
+        let handle = task::spawn_with_priority(&line, Priority::Background, async move {
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            execute_command(&command, &args).await
+        });
+
+        let number = self.next_number;
+        self.next_number += 1;
+        let started = format!("[{}] task {}", number, handle.id().as_u64());
+        self.jobs.push(Job {
+            number,
+            command: line,
+            handle,
+        });
+        vec![started]
+    }
+
+    /// Runs `command` if it's one of the job control commands.
+    async fn execute(&mut self, command: &str, args: &[&str]) -> Option<Vec<String>> {
+        let mut output = Vec::new();
+        match command {
+            "jobs" => {
+                for job in &self.jobs {
+                    output.push(format!(
+                        "[{}] {:<8} {}",
+                        job.number,
+                        if job.handle.is_finished() {
+                            "done"
+                        } else {
+                            "running"
+                        },
+                        job.command
+                    ));
+                }
+            }
+            // Waits for a job and shows its output
+            "fg" => match self.take(args) {
+                Some(job) => match job.handle.await {
+                    Ok(lines) => output = lines,
+                    Err(e) => output.push(format!("[{}] {}", job.number, e)),
+                },
+                None => output.push("Usage: fg <job>".to_string()),
+            },
+            "kill" => match self.take(args) {
+                Some(job) if job.handle.is_finished() => {
+                    output.push(format!("[{}] had already finished", job.number));
+                }
+                Some(job) => {
+                    job.handle.abort();
+                    output.push(format!("[{}] killed", job.number));
+                }
+                None => output.push("Usage: kill <job>".to_string()),
+            },
+            _ => return None,
+        }
+        Some(output)
+    }
+
+    // Removes the job whose number is the first argument
+    fn take(&mut self, args: &[&str]) -> Option<Job> {
+        let number: usize = args.first()?.trim_start_matches('%').parse().ok()?;
+        let index = self.jobs.iter().position(|job| job.number == number)?;
+        Some(self.jobs.remove(index))
+    }
+}
+
+async fn execute_command(command: &str, args: &[&str]) -> Vec<String> {
     let mut output: Vec<String> = Vec::new();
 
     match command {
@@ -88,15 +355,36 @@ fn execute_command(command: &str, args: &[&str]) -> Vec<String> {
             output.push("  cat [filename] - Display contents of a file".to_string());
             output.push("  write [filename] [content] - Create or overwrite a file".to_string());
             output.push("  disk_info - Show information about the disk".to_string());
+
+            // system commands
+            output.push("SYSTEM COMMANDS:".to_string());
+            output.push("  cpus - List the processors and their state".to_string());
+            output.push("  irqstat - Show interrupt counts per IRQ line".to_string());
+            output.push("  threads - List the kernel threads".to_string());
+            output.push("  ps - List the async tasks and their CPU time".to_string());
+            output.push("  top - Show which tasks use the CPU, refreshing".to_string());
+            output.push("  usertest - Run the ring 3 syscall test program".to_string());
+            output.push("  run <file> [args] - Run an ELF executable from the disk".to_string());
+            output.push(
+                "  start <file> / wait <pid> - Start one in the background, wait for it"
+                    .to_string(),
+            );
+            output.push("  procs - List the processes".to_string());
+            output.push("  <command> & - Run a command as a background job".to_string());
+            output.push("  jobs / fg <job> / kill <job> - List, wait for or stop jobs".to_string());
+            output.push("  gdb - Stop and wait for a debugger on COM2".to_string());
+            output.push(
+                "  dmesg [level] - Show the kernel log, optionally only up to a level".to_string(),
+            );
+            output.push("  loglevel [sink] [level] - Show or set the log levels".to_string());
+            output.push("  monitor [command] - Open the debug monitor (or press F12)".to_string());
         }
         "echo" => {
             let echoed = args.join(" ");
             output.push(echoed);
         }
         "clear" => {
-            if let Some(writer) = WRITER.lock().as_mut() {
-                writer.clear();
-            }
+            framebuffer::clear_console();
         }
         "exit" => {
             panic!("exit command issued!");
@@ -151,18 +439,18 @@ fn execute_command(command: &str, args: &[&str]) -> Vec<String> {
             let filename = args[0];
 
             // Lock the filesystem
-            let mut fs_lock = FILESYSTEM.lock();
+            let mut fs_lock = FILESYSTEM.lock().await;
 
             if let Some(fs) = fs_lock.as_mut() {
                 // Try to read the file
-                match fs.read_file(filename) {
-                    Some(data) => {
+                match fs.read_file_async(filename).await {
+                    Ok(data) => {
                         // Convert bytes to string (lossy ensures it doesn't crash on binary data)
                         let content = String::from_utf8_lossy(&data);
                         println!("{}", content);
                     }
-                    None => {
-                        println!("File not found: {}", filename);
+                    Err(e) => {
+                        println!("{}: {}", e, filename);
                     }
                 }
             } else {
@@ -171,12 +459,16 @@ fn execute_command(command: &str, args: &[&str]) -> Vec<String> {
         }
 
         "ls" => {
-            let mut fs_lock = FILESYSTEM.lock();
+            let mut fs_lock = FILESYSTEM.lock().await;
             if let Some(fs) = fs_lock.as_mut() {
-                println!("Directory listing:");
-                let files = fs.list_root();
-                for file in files {
-                    println!("  {}", file);
+                match fs.list_root_async().await {
+                    Ok(files) => {
+                        println!("Directory listing:");
+                        for file in files {
+                            println!("  {}", file);
+                        }
+                    }
+                    Err(e) => println!("Error listing directory: {}", e),
                 }
             } else {
                 println!("Filesystem not initialized!");
@@ -194,7 +486,7 @@ fn execute_command(command: &str, args: &[&str]) -> Vec<String> {
             // Join the rest of the arguments into the content string
             let content = args[1..].join(" ");
 
-            let mut fs_lock = FILESYSTEM.lock();
+            let mut fs_lock = FILESYSTEM.lock().await;
             if let Some(fs) = fs_lock.as_mut() {
                 match fs.create_file(filename, content.as_bytes()) {
                     Ok(_) => println!("File '{}' written successfully.", filename),
@@ -206,7 +498,7 @@ fn execute_command(command: &str, args: &[&str]) -> Vec<String> {
         }
 
         "disk_info" => {
-            let mut fs_lock = FILESYSTEM.lock();
+            let mut fs_lock = FILESYSTEM.lock().await;
             if let Some(fs) = fs_lock.as_mut() {
                 // Access the underlying ATA drive from the FAT driver
                 match fs.drive.get_total_sectors() {
@@ -223,6 +515,196 @@ fn execute_command(command: &str, args: &[&str]) -> Vec<String> {
             }
         }
 
+        "cpus" => {
+            let cpus = smp::CPUS.lock();
+            output.push(format!("{} CPU(s):", cpus.len()));
+            for cpu in cpus.iter() {
+                output.push(format!(
+                    "  CPU {}: APIC ID {}, ACPI ID {}, {}{}",
+                    cpu.index,
+                    cpu.apic_id,
+                    cpu.processor_id,
+                    if cpu.online { "online" } else { "offline" },
+                    if cpu.is_bsp { " (BSP)" } else { "" }
+                ));
+                if let Some(area) = percpu::get(cpu.index) {
+                    output.push(format!(
+                        "    irqs: {}, syscalls: {}, polls: {}, switches: {}",
+                        area.stats.interrupts.load(Ordering::Relaxed),
+                        area.stats.syscalls.load(Ordering::Relaxed),
+                        area.stats.task_polls.load(Ordering::Relaxed),
+                        area.stats.context_switches.load(Ordering::Relaxed)
+                    ));
+                }
+            }
+        }
+
+        "irqstat" => {
+            output.push("IRQ  VECTOR  HANDLERS  MASKED  COUNT".to_string());
+            for line in 0..interrupts::IRQ_LINES as u8 {
+                let handlers = interrupts::irq_handler_count(line);
+                let count = interrupts::irq_count(line);
+                if handlers == 0 && count == 0 {
+                    continue;
+                }
+                output.push(format!(
+                    "{:>3}  {:>6}  {:>8}  {:>6}  {}",
+                    line,
+                    interrupts::irq_vector(line),
+                    handlers,
+                    if interrupts::is_irq_masked(line) {
+                        "yes"
+                    } else {
+                        "no"
+                    },
+                    count
+                ));
+            }
+            output.push(format!("Spurious: {}", interrupts::spurious_count()));
+        }
+
+        "ps" => {
+            output.push(
+                "  ID  PRIORITY     STATE       POLLS    CPU TIME  CPU%  WOKEN   SLOW  NAME"
+                    .to_string(),
+            );
+            let now = Instant::now();
+            for task in executor::snapshot() {
+                let cpu_time = task.poll_time;
+                let lifetime = now.duration_since(task.spawned_at);
+                let woken = match task.last_woken {
+                    Some(woken) => format!("{}s", now.duration_since(woken).as_secs()),
+                    None => "-".to_string(),
+                };
+                output.push(format!(
+                    "{:>4}  {:<11}  {:<8}  {:>7}  {:>5}.{:03}s  {:>3}%  {:>5}  {:>5}  {}",
+                    task.id.as_u64(),
+                    task.priority,
+                    task.state,
+                    task.polls,
+                    cpu_time.as_secs(),
+                    cpu_time.subsec_millis(),
+                    per_mille(cpu_time, lifetime) / 10,
+                    woken,
+                    task.overruns,
+                    task.name
+                ));
+            }
+        }
+
+        "start" => {
+            let Some(&filename) = args.first() else {
+                output.push("Usage: start <file> [args...]".to_string());
+                return output;
+            };
+            match load_program(filename)
+                .await
+                .and_then(|program| process::spawn(filename, None, program, args))
+            {
+                Ok(process) => {
+                    output.push(format!("Started {} as pid {}", filename, process.pid()))
+                }
+                Err(e) => output.push(format!("{}: {}", filename, e)),
+            }
+        }
+
+        "wait" => {
+            let Some(pid) = args
+                .first()
+                .and_then(|pid| pid.parse::<process::Pid>().ok())
+            else {
+                output.push("Usage: wait <pid>".to_string());
+                return output;
+            };
+            match process::wait(pid).await {
+                Ok(status) => output.push(format!("pid {} exited with code {}", pid, status)),
+                Err(e) => output.push(format!("wait: {}", e)),
+            }
+        }
+
+        "procs" => {
+            output.push(
+                "PID  PPID  NAME          STATE    THREAD  RIP               STATUS".to_string(),
+            );
+            for process in process::processes() {
+                output.push(format!(
+                    "{:>3}  {:>4}  {:<12}  {:<7}  {:>6}  {:#016x}  {}",
+                    process.pid(),
+                    process.parent().map_or(0, |pid| pid.as_u64()),
+                    process.name(),
+                    process.state(),
+                    process.thread().map_or(0, |id| id.as_u64()),
+                    process.context().rip,
+                    process
+                        .exit_status()
+                        .map_or(String::from("-"), |status| status.to_string())
+                ));
+            }
+        }
+
+        "threads" => {
+            output.push(" ID  NAME          STATE    STACK  SWITCHES".to_string());
+            for thread in thread::threads() {
+                output.push(format!(
+                    "{:>3}  {:<12}  {:<7}  {:>4}K  {}",
+                    thread.id().as_u64(),
+                    thread.name(),
+                    thread.state(),
+                    thread.stack_size() / 1024,
+                    thread.switches()
+                ));
+            }
+        }
+
+        "dmesg" => {
+            // e.g. `dmesg warn` shows only warnings and errors
+            let level = match args.first() {
+                Some(level) => LevelFilter::from_str(level).ok(),
+                None => Some(LevelFilter::Trace),
+            };
+            match level {
+                Some(level) => output.extend(logger::dmesg(level)),
+                None => output.push("Levels: off, error, warn, info, debug, trace".to_string()),
+            }
+        }
+
+        "loglevel" => match args {
+            [] => {
+                output.push(format!("log: {}", logger::level()));
+                for sink in Sink::ALL {
+                    output.push(format!("{}: {}", sink.name(), logger::sink_level(sink)));
+                }
+            }
+            [level] => match LevelFilter::from_str(level) {
+                Ok(level) => logger::set_level(level),
+                Err(_) => output.push("Usage: loglevel [serial|console] [level]".to_string()),
+            },
+            [sink, level, ..] => match (Sink::from_name(sink), LevelFilter::from_str(level)) {
+                (Some(sink), Ok(level)) => logger::set_sink_level(sink, level),
+                _ => output.push("Usage: loglevel [serial|console] [level]".to_string()),
+            },
+        },
+
+        // Without arguments the shell switches to the monitor prompt, which a
+        // background job (`monitor &`) can't
+        "monitor" => match args {
+            [command, rest @ ..] => output = monitor::execute(command, rest),
+            [] => output.push("Usage: monitor <command> [args...]".to_string()),
+        },
+
+        "gdb" => {
+            if gdb::is_attached() {
+                output.push("GDB is already attached".to_string());
+            } else {
+                println!(
+                    "Waiting for GDB on COM2 (kernel loaded at {:#x})...",
+                    gdb::kernel_image_offset()
+                );
+                gdb::attach();
+                output.push("GDB attached".to_string());
+            }
+        }
+
         _ => {
             println!("Unknown command: {}", command);
         }
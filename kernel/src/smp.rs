@@ -0,0 +1,202 @@
+use crate::time::{self, Duration, Instant};
+use crate::{acpi, apic, gdt, interrupts, memory, percpu, watchdog};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::{error, info, warn};
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Physical (and identity-mapped virtual) address of the real-mode trampoline.
+/// Must be page aligned and below 1MiB; the SIPI vector is `TRAMPOLINE_BASE >> 12`.
+pub const TRAMPOLINE_BASE: u64 = 0x8000;
+
+const AP_STACK_SIZE: usize = 4096 * 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cpu {
+    pub index: usize,
+    pub apic_id: u8,
+    pub processor_id: u8,
+    pub is_bsp: bool,
+    pub online: bool,
+}
+
+pub static CPUS: Mutex<Vec<Cpu>> = Mutex::new(Vec::new());
+
+// Set by an AP once it reached `ap_main`
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Enumerates the CPUs from the ACPI MADT and boots every application processor.
+/// Needs the heap and the global mapper.
+pub fn init(rsdp_addr: Option<u64>) {
+    let madt = match rsdp_addr.and_then(acpi::parse_madt) {
+        Some(madt) => madt,
+        None => {
+            warn!("No MADT found, running on the BSP only");
+            CPUS.lock().push(Cpu {
+                index: 0,
+                apic_id: 0,
+                processor_id: 0,
+                is_bsp: true,
+                online: true,
+            });
+            return;
+        }
+    };
+
+    apic::init_bsp(madt.local_apic_address);
+    let bsp_apic_id = apic::id();
+
+    let mut cpus: Vec<Cpu> = Vec::new();
+    for processor in &madt.processors {
+        let is_bsp = processor.apic_id == bsp_apic_id;
+        cpus.push(Cpu {
+            index: 0,
+            apic_id: processor.apic_id,
+            processor_id: processor.processor_id,
+            is_bsp,
+            online: is_bsp,
+        });
+    }
+    // BSP is always CPU 0, APs follow in MADT order
+    cpus.sort_by_key(|cpu| !cpu.is_bsp);
+    for (index, cpu) in cpus.iter_mut().enumerate() {
+        cpu.index = index;
+    }
+    *CPUS.lock() = cpus.clone();
+
+    if cpus.len() > 1 {
+        install_trampoline();
+    }
+
+    for cpu in cpus.iter().filter(|cpu| !cpu.is_bsp) {
+        if start_ap(cpu) {
+            CPUS.lock()[cpu.index].online = true;
+            info!("CPU {} (APIC {}) online", cpu.index, cpu.apic_id);
+        } else {
+            warn!("CPU {} (APIC {}) did not respond", cpu.index, cpu.apic_id);
+        }
+    }
+
+    info!("{} of {} CPUs online", online_count(), cpus.len());
+}
+
+pub fn online_count() -> usize {
+    CPUS.lock().iter().filter(|cpu| cpu.online).count()
+}
+
+fn trampoline_symbol_offset(symbol: *const u8) -> u64 {
+    symbol as u64 - (&raw const ap_trampoline_start) as u64
+}
+
+/// Writes a value into one of the data slots of the copied trampoline.
+unsafe fn write_trampoline_slot(symbol: *const u8, value: u64) {
+    let slot = TRAMPOLINE_BASE + trampoline_symbol_offset(symbol);
+    unsafe {
+        core::ptr::write_volatile(
+            memory::phys_to_virt(PhysAddr::new(slot)).as_mut_ptr::<u64>(),
+            value,
+        );
+    }
+}
+
+fn install_trampoline() {
+    let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_BASE));
+    let page = Page::containing_address(VirtAddr::new(TRAMPOLINE_BASE));
+
+    // The APs turn paging on while executing in this page, so it needs an identity mapping
+    memory::map_page(
+        page,
+        frame,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+    )
+    .expect("failed to identity map the SMP trampoline");
+
+    let start = &raw const ap_trampoline_start;
+    let len = trampoline_symbol_offset(&raw const ap_trampoline_end) as usize;
+    assert!(len <= 4096, "SMP trampoline larger than a page");
+
+    unsafe {
+        let dest = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_BASE)).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(start, dest, len);
+    }
+}
+
+/// Runs the INIT-SIPI-SIPI sequence for one AP and waits for it to check in.
+fn start_ap(cpu: &Cpu) -> bool {
+    let (pml4, _) = Cr3::read();
+    let cr3 = pml4.start_address().as_u64();
+    if cr3 > u32::MAX as u64 {
+        // The trampoline loads CR3 while still in 32-bit mode
+        error!("PML4 above 4GiB, cannot start APs");
+        return false;
+    }
+
+    let stack = vec![0u8; AP_STACK_SIZE].leak();
+    let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xF;
+
+    AP_STARTED.store(false, Ordering::SeqCst);
+    unsafe {
+        write_trampoline_slot(&raw const ap_trampoline_cr3, cr3);
+        write_trampoline_slot(&raw const ap_trampoline_stack, stack_top);
+        write_trampoline_slot(&raw const ap_trampoline_entry, ap_main as *const () as u64);
+        write_trampoline_slot(&raw const ap_trampoline_cpu, cpu.index as u64);
+    }
+
+    let vector_page = (TRAMPOLINE_BASE >> 12) as u8;
+
+    apic::send_init(cpu.apic_id);
+    time::busy_wait(Duration::from_millis(10));
+
+    // The spec wants a second SIPI if the first one got lost
+    for _ in 0..2 {
+        apic::send_startup(cpu.apic_id, vector_page);
+        if wait_for_ap(Duration::from_millis(100)) {
+            return true;
+        }
+    }
+    false
+}
+
+fn wait_for_ap(timeout: Duration) -> bool {
+    let deadline = Instant::now().checked_add(timeout).unwrap();
+    while Instant::now() < deadline {
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    AP_STARTED.load(Ordering::SeqCst)
+}
+
+/// First Rust code an AP runs, on the stack the BSP allocated for it.
+extern "C" fn ap_main(cpu_index: u64) -> ! {
+    gdt::init_ap();
+    percpu::init_ap(cpu_index as usize);
+    interrupts::init_idt();
+    apic::init_local();
+
+    AP_STARTED.store(true, Ordering::SeqCst);
+    watchdog::init_ap(cpu_index as usize);
+
+    // Nothing is scheduled on APs yet, so just park
+    x86_64::instructions::interrupts::enable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+global_asm!(include_str!("smp_trampoline.asm"), base = const TRAMPOLINE_BASE);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_cpu: u8;
+}
@@ -0,0 +1,103 @@
+// Application processors start here in real mode after the SIPI.
+// The BSP copies everything between ap_trampoline_start and ap_trampoline_end
+// to {base} and fills in the data slots at the end before each SIPI.
+.global ap_trampoline_start
+.global ap_trampoline_end
+.global ap_trampoline_cr3
+.global ap_trampoline_stack
+.global ap_trampoline_entry
+.global ap_trampoline_cpu
+
+// Memory operands may only name one symbol, so precompute these offsets
+.set AP_GDT_PTR_OFFSET, ap_gdt_ptr - ap_trampoline_start
+.set AP_CR3_OFFSET, ap_trampoline_cr3 - ap_trampoline_start
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+
+    // CS = {base} >> 4 and IP = 0, so offsets from the start are DS-relative
+    mov ax, cs
+    mov ds, ax
+    lgdt [AP_GDT_PTR_OFFSET]
+
+    // Protection Enable
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+
+    // ljmp 0x08:ap_protected_mode (32-bit offset)
+    .byte 0x66, 0xEA
+    .long {base} + (ap_protected_mode - ap_trampoline_start)
+    .word 0x08
+
+.code32
+ap_protected_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    // PAE | PGE
+    mov eax, cr4
+    or eax, (1 << 5) | (1 << 7)
+    mov cr4, eax
+
+    // The kernel's PML4 (must be below 4GiB)
+    mov eax, dword ptr [{base} + AP_CR3_OFFSET]
+    mov cr3, eax
+
+    // EFER: Long Mode Enable | No-Execute Enable
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    // Paging | Write Protect
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)
+    mov cr0, eax
+
+    // ljmp 0x18:ap_long_mode
+    .byte 0xEA
+    .long {base} + (ap_long_mode - ap_trampoline_start)
+    .word 0x18
+
+.code64
+ap_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    // RIP-relative, so these read the copy at {base}
+    mov rsp, qword ptr [rip + ap_trampoline_stack]
+    mov rdi, qword ptr [rip + ap_trampoline_cpu]
+    mov rax, qword ptr [rip + ap_trampoline_entry]
+    call rax
+
+ap_halt:
+    hlt
+    jmp ap_halt
+
+.align 8
+ap_gdt:
+    .quad 0
+    .quad 0x00CF9A000000FFFF // 0x08: 32-bit code
+    .quad 0x00CF92000000FFFF // 0x10: data
+    .quad 0x00AF9A000000FFFF // 0x18: 64-bit code
+ap_gdt_ptr:
+    .word ap_gdt_ptr - ap_gdt - 1
+    .long {base} + (ap_gdt - ap_trampoline_start)
+
+.align 8
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_cpu:
+    .quad 0
+ap_trampoline_end:
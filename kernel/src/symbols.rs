@@ -0,0 +1,110 @@
+use log::warn;
+use spin::Once;
+
+// See `write_symbol_table` in build.rs for the layout
+const MAGIC: &[u8; 8] = b"SAMUXSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 24;
+
+struct SymbolTable {
+    entries: &'static [u8],
+    names: &'static [u8],
+    count: usize,
+    // Where the bootloader actually placed the (position independent) kernel
+    load_offset: u64,
+}
+
+static TABLE: Once<SymbolTable> = Once::new();
+
+/// A kernel function, with its runtime address.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// Loads the table the build embedded as the boot ramdisk.
+/// `load_offset` is `BootInfo::kernel_image_offset`.
+pub fn init(data: &'static [u8], load_offset: u64) {
+    if data.len() < HEADER_SIZE || &data[..8] != MAGIC {
+        warn!("No kernel symbol table found");
+        return;
+    }
+
+    let count = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+    let names_start = HEADER_SIZE + count * ENTRY_SIZE;
+    if data.len() < names_start {
+        warn!("Kernel symbol table is truncated");
+        return;
+    }
+
+    TABLE.call_once(|| SymbolTable {
+        entries: &data[HEADER_SIZE..names_start],
+        names: &data[names_start..],
+        count,
+        load_offset,
+    });
+}
+
+pub fn is_loaded() -> bool {
+    TABLE.is_completed()
+}
+
+/// Finds the function containing the runtime address `addr`.
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    let table = TABLE.get()?;
+    let link_addr = addr.checked_sub(table.load_offset)?;
+
+    // Index of the last symbol starting at or below `link_addr`
+    let (mut low, mut high) = (0, table.count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if table.entry_addr(mid) <= link_addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let index = low.checked_sub(1)?;
+
+    let symbol = table.symbol(index)?;
+    let start = symbol.addr;
+    // Size 0 means unknown (hand written assembly), accept anything up to the next symbol
+    if symbol.size != 0 && link_addr >= start + symbol.size {
+        return None;
+    }
+
+    Some(Symbol {
+        addr: start + table.load_offset,
+        ..symbol
+    })
+}
+
+impl SymbolTable {
+    fn entry(&self, index: usize) -> &[u8] {
+        &self.entries[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE]
+    }
+
+    fn entry_addr(&self, index: usize) -> u64 {
+        u64::from_le_bytes(self.entry(index)[0..8].try_into().unwrap())
+    }
+
+    /// The symbol at `index`, with its link-time address.
+    fn symbol(&self, index: usize) -> Option<Symbol> {
+        let entry = self.entry(index);
+        let field =
+            |offset: usize| u32::from_le_bytes(entry[offset..offset + 4].try_into().unwrap());
+
+        let size = field(8) as u64;
+        let name_offset = field(12) as usize;
+        let name_len = field(16) as usize;
+
+        let name = self.names.get(name_offset..name_offset + name_len)?;
+        Some(Symbol {
+            name: core::str::from_utf8(name).ok()?,
+            addr: self.entry_addr(index),
+            size,
+        })
+    }
+}
@@ -1,26 +1,162 @@
 use x86_64::VirtAddr;
-use x86_64::registers::model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star};
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
 use x86_64::registers::rflags::RFlags;
 
+use alloc::{vec, vec::Vec};
 use core::arch::global_asm;
+use core::fmt;
+use core::sync::atomic::Ordering;
 
-use crate::gdt;
+use crate::file::{self, File, FileRef, Stat};
+use crate::time::{self, Duration};
+use crate::{gdt, percpu, process, thread, user};
 
+// The syscall ABI. A program puts the syscall number in RAX and up to six
+// arguments in RDI, RSI, RDX, R10, R8 and R9, then executes `syscall`. The
+// result comes back in RAX: a value >= 0 on success, or a negated `Errno`.
+// RCX and R11 are clobbered (the CPU keeps RIP and RFLAGS there), every
+// other register is preserved.
+
+/// `read(fd, buf, len)`: reads up to `len` bytes. The console blocks until
+/// there is keyboard input. Returns the number of bytes read, 0 at the end
+/// of a file.
+pub const SYS_READ: u64 = 0;
+/// `write(fd, buf, len)`: writes `len` bytes. Returns `len`.
+pub const SYS_WRITE: u64 = 1;
+/// `exit(status)`: ends the process. Doesn't return.
+pub const SYS_EXIT: u64 = 2;
+/// `yield()`: lets other threads run. Returns 0.
+pub const SYS_YIELD: u64 = 3;
+/// `getpid()`: the PID of the calling process.
+pub const SYS_GETPID: u64 = 4;
+/// `sleep(ms)`: blocks for at least `ms` milliseconds. Returns 0.
+pub const SYS_SLEEP: u64 = 5;
+/// `uptime()`: milliseconds since boot.
+pub const SYS_UPTIME: u64 = 6;
+/// `open(path, flags)`: opens the file named by the NUL-terminated string
+/// at `path` with `file::O_*` flags. Returns the lowest free descriptor.
+pub const SYS_OPEN: u64 = 7;
+/// `close(fd)`: frees a descriptor, writing its file back if it changed.
+/// Returns 0.
+pub const SYS_CLOSE: u64 = 8;
+/// `lseek(fd, offset, whence)`: moves the offset by `offset` from a
+/// `file::SEEK_*` origin. Returns the new offset.
+pub const SYS_LSEEK: u64 = 9;
+/// `fstat(fd, buf)`: fills in a `file::Stat` at `buf`. Returns 0.
+pub const SYS_FSTAT: u64 = 10;
+/// `dup2(old, new)`: makes `new` refer to the file of `old`, closing what
+/// it referred to before. Returns `new`.
+pub const SYS_DUP2: u64 = 11;
+/// `brk(end)`: grows the heap, which starts at `user::HEAP_START`, to end
+/// at `end`, with zeroed memory. It never shrinks, so `brk(0)` returns the
+/// current end. Returns the new end.
+pub const SYS_BRK: u64 = 12;
+
+/// Why a syscall failed. User programs see the negated value in RAX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    /// No such file
+    ENOENT = 2,
+    /// Disk error
+    EIO = 5,
+    /// Bad file descriptor
+    EBADF = 9,
+    /// Out of memory
+    ENOMEM = 12,
+    /// Bad address
+    EFAULT = 14,
+    /// Invalid argument
+    EINVAL = 22,
+    /// Too many open files
+    EMFILE = 24,
+    /// File too large
+    EFBIG = 27,
+    /// Not seekable
+    ESPIPE = 29,
+    /// Name too long
+    ENAMETOOLONG = 36,
+    /// No such syscall
+    ENOSYS = 38,
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Errno::ENOENT => "no such file",
+            Errno::EIO => "disk error",
+            Errno::EBADF => "bad file descriptor",
+            Errno::ENOMEM => "out of memory",
+            Errno::EFAULT => "bad address",
+            Errno::EINVAL => "invalid argument",
+            Errno::EMFILE => "too many open files",
+            Errno::EFBIG => "file too large",
+            Errno::ESPIPE => "not seekable",
+            Errno::ENAMETOOLONG => "name too long",
+            Errno::ENOSYS => "no such syscall",
+        };
+        f.write_str(description)
+    }
+}
+
+/// The user registers `syscall_dispatcher` saves on the kernel stack and
+/// restores on the way out, in memory order.
 #[repr(C)]
-pub struct KernelScratch {
-    pub kernel_stack_top: u64,   // Offset 0
-    pub user_stack_scratch: u64, // Offset 8
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    /// The syscall number
+    pub rax: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
 }
 
-// 16KB system call stack
-const SYSCALL_STACK_SIZE: usize = 4096 * 4;
-static mut SYSCALL_STACK: [u8; SYSCALL_STACK_SIZE] = [0; SYSCALL_STACK_SIZE];
+// syscall_asm.asm pushes exactly this much
+const _: () = assert!(size_of::<SyscallFrame>() == 16 * 8);
 
-// The instance that GS will point to
-static mut KERNEL_SCRATCH: KernelScratch = KernelScratch {
-    kernel_stack_top: 0,
-    user_stack_scratch: 0,
-};
+impl SyscallFrame {
+    /// The six argument registers, in ABI order.
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+// Longest path `open` takes, without the NUL
+const PATH_MAX: usize = 255;
+// Most bytes `read` and `write` copy through the kernel at once: a process
+// can map far more than the kernel heap holds
+const IO_CHUNK: u64 = 4096;
+
+type SyscallFn = fn(&[u64; 6]) -> Result<u64, Errno>;
+
+// Indexed by syscall number
+static SYSCALL_TABLE: [(&str, SyscallFn); 13] = [
+    ("read", sys_read),
+    ("write", sys_write),
+    ("exit", sys_exit),
+    ("yield", sys_yield),
+    ("getpid", sys_getpid),
+    ("sleep", sys_sleep),
+    ("uptime", sys_uptime),
+    ("open", sys_open),
+    ("close", sys_close),
+    ("lseek", sys_lseek),
+    ("fstat", sys_fstat),
+    ("dup2", sys_dup2),
+    ("brk", sys_brk),
+];
 
 pub fn init_syscall() {
     unsafe {
@@ -44,13 +180,9 @@ pub fn init_syscall() {
         .unwrap();
 
         SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG);
-
-        let stack_top = VirtAddr::from_ptr(&raw const SYSCALL_STACK) + SYSCALL_STACK_SIZE as u64;
-        KERNEL_SCRATCH.kernel_stack_top = stack_top.as_u64();
-
-        let scratch_addr = VirtAddr::from_ptr(&raw const KERNEL_SCRATCH);
-        KernelGsBase::write(scratch_addr);
     }
+    // `syscall_dispatcher` switches to the stack in the per-CPU area (GS),
+    // which `thread::schedule` points at the running thread's kernel stack
 }
 
 pub unsafe fn enter_userspace(entry_point: u64, stack_pointer: u64) -> ! {
@@ -60,6 +192,9 @@ pub unsafe fn enter_userspace(entry_point: u64, stack_pointer: u64) -> ! {
 
     unsafe {
         core::arch::asm!(
+            // No interrupt may see the user's GS base in ring 0; IRETQ
+            // enables them again
+            "cli",
             "swapgs",
             "push {ss:r}",
             "push {rsp}",
@@ -78,16 +213,149 @@ pub unsafe fn enter_userspace(entry_point: u64, stack_pointer: u64) -> ! {
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn syscall_rust_handler(
-    rdi: usize,
-    rsi: usize,
-    rdx: usize,
-    _r10: usize,
-    _r8: usize,
-    _r9: usize,
-) -> usize {
-    crate::serial_println!("SYSCALL CAUGHT! Args: {}, {}, {}", rdi, rsi, rdx);
-    0
+extern "C" fn syscall_rust_handler(frame: &mut SyscallFrame) -> u64 {
+    percpu!(stats).syscalls.fetch_add(1, Ordering::Relaxed);
+    // We're on the calling thread's own kernel stack, so it may be preempted
+    x86_64::instructions::interrupts::enable();
+    process::save_context(frame);
+
+    let result = match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some((name, syscall)) => {
+            log::trace!("syscall {} ({})", name, frame.rax);
+            syscall(&frame.args())
+        }
+        None => Err(Errno::ENOSYS),
+    };
+
+    match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    }
+}
+
+fn sys_read(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [fd, buf, len, ..] = *args;
+    let file = open_file(fd)?;
+    // Anything longer is a short read
+    let len = len.min(IO_CHUNK);
+    // Before waiting for input
+    if !user::contains(buf, len, true) {
+        return Err(Errno::EFAULT);
+    }
+    let mut buffer = vec![0; len as usize];
+    let count = file::read(&file, &mut buffer)?;
+    user::copy_to_user(buf, &buffer[..count])?;
+    Ok(count as u64)
+}
+
+fn sys_write(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [fd, buf, len, ..] = *args;
+    let file = open_file(fd)?;
+    if !user::contains(buf, len, false) {
+        return Err(Errno::EFAULT);
+    }
+    let mut written = 0;
+    loop {
+        let chunk = (len - written).min(IO_CHUNK);
+        match copy_in(buf + written, chunk).and_then(|data| file.lock().write(&data)) {
+            Ok(count) => written += count as u64,
+            // What did get written makes a short write
+            Err(_) if written > 0 => return Ok(written),
+            Err(errno) => return Err(errno),
+        }
+        if written >= len {
+            return Ok(written);
+        }
+    }
+}
+
+fn sys_exit(args: &[u64; 6]) -> Result<u64, Errno> {
+    process::exit(args[0] as i64);
+}
+
+fn sys_yield(_args: &[u64; 6]) -> Result<u64, Errno> {
+    thread::yield_now();
+    Ok(0)
+}
+
+fn sys_getpid(_args: &[u64; 6]) -> Result<u64, Errno> {
+    let process = process::current().ok_or(Errno::EINVAL)?;
+    Ok(process.pid().as_u64())
+}
+
+fn sys_sleep(args: &[u64; 6]) -> Result<u64, Errno> {
+    process::blocked(|| thread::sleep(Duration::from_millis(args[0])));
+    Ok(0)
+}
+
+fn sys_uptime(_args: &[u64; 6]) -> Result<u64, Errno> {
+    Ok(time::uptime().as_millis() as u64)
+}
+
+fn sys_open(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [path, flags, ..] = *args;
+    let name = user::strncpy_from_user(path, PATH_MAX)?;
+    let flags = u32::try_from(flags).map_err(|_| Errno::EINVAL)?;
+    let process = process::current().ok_or(Errno::EINVAL)?;
+
+    let file = File::open(&name, flags)?;
+    process.files().lock().insert(file)
+}
+
+fn sys_close(args: &[u64; 6]) -> Result<u64, Errno> {
+    let process = process::current().ok_or(Errno::EBADF)?;
+    let file = process.files().lock().remove(args[0])?;
+    file::close(file)?;
+    Ok(0)
+}
+
+fn sys_lseek(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [fd, offset, whence, ..] = *args;
+    open_file(fd)?.lock().seek(offset as i64, whence)
+}
+
+fn sys_fstat(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [fd, buf, ..] = *args;
+    let stat = open_file(fd)?.lock().stat();
+    let bytes =
+        unsafe { core::slice::from_raw_parts(&raw const stat as *const u8, size_of::<Stat>()) };
+    user::copy_to_user(buf, bytes)?;
+    Ok(0)
+}
+
+fn sys_dup2(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [old, new, ..] = *args;
+    let process = process::current().ok_or(Errno::EBADF)?;
+    let replaced = process.files().lock().dup2(old, new)?;
+    // Like `close`, except that errors are lost
+    if let Some(file) = replaced {
+        let _ = file::close(file);
+    }
+    Ok(new)
+}
+
+fn sys_brk(args: &[u64; 6]) -> Result<u64, Errno> {
+    process::brk(args[0]).map_err(|e| {
+        log::debug!("brk({:#x}): {}", args[0], e);
+        Errno::ENOMEM
+    })
+}
+
+// The file descriptor `fd` of the calling process refers to
+fn open_file(fd: u64) -> Result<FileRef, Errno> {
+    let process = process::current().ok_or(Errno::EBADF)?;
+    process.files().lock().get(fd)
+}
+
+// A kernel copy of the `len` bytes at user address `addr`, at most
+// `IO_CHUNK` of them
+fn copy_in(addr: u64, len: u64) -> Result<Vec<u8>, Errno> {
+    if !user::contains(addr, len, false) {
+        return Err(Errno::EFAULT);
+    }
+    let mut data = vec![0; len as usize];
+    user::copy_from_user(&mut data, addr)?;
+    Ok(data)
 }
 
 global_asm!(include_str!("syscall_asm.asm"));
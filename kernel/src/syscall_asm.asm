@@ -9,9 +9,17 @@ syscall_dispatcher:
     // Load Kernel Stack Pointer from GS offset 0
     mov rsp, qword ptr gs:[0]
 
-    // Save registers
+    // Build a SyscallFrame (syscall.rs), last field first
+    push qword ptr gs:[8]  // user RSP
     push r11  // RFLAGS
     push rcx  // RIP
+    push rax  // syscall number
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
     push rbp
     push rbx
     push r12
@@ -19,23 +27,31 @@ syscall_dispatcher:
     push r14
     push r15
 
-    // Handle arguments (R10 -> RCX)
-    mov rcx, r10
-    
+    mov rdi, rsp
     call syscall_rust_handler
 
-    // Restore registers
+    // The handler runs with interrupts on; nothing may interrupt the way back
+    cli
+
+    // Restore registers, leaving the result in RAX
     pop r15
     pop r14
     pop r13
     pop r12
     pop rbx
     pop rbp
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    add rsp, 8
     pop rcx
     pop r11
 
     // Restore User Stack Pointer
-    mov rsp, qword ptr gs:[8]
+    pop rsp
     
     swapgs
     sysretq
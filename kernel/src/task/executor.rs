@@ -1,15 +1,320 @@
-use super::Task;
-use alloc::{sync::Arc, task::Wake};
+use super::join::{self, JoinHandle};
+use super::{Priority, Task, TaskId};
+use crate::time::{self, Duration, Instant};
+use crate::{percpu, thread};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::{string::String, sync::Arc, task::Wake, vec::Vec};
+use core::fmt;
+use core::future::{Future, poll_fn};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
-use lazy_static::lazy_static;
+use spin::Mutex;
 use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
-// Fixed size queue for waiting tasks.
-const MAX_TASKS: usize = 100;
+// Every task that hasn't finished yet
+static TASKS: Mutex<BTreeMap<TaskId, Arc<TaskEntry>>> = Mutex::new(BTreeMap::new());
 
-lazy_static! {
-    static ref TASK_QUEUE: ArrayQueue<Arc<Task>> = ArrayQueue::new(MAX_TASKS);
+// Tasks to poll, each at most once thanks to `TaskWaker::queued`.
+// Wakers push from interrupt handlers, so this is locked with interrupts off
+// and must never allocate: `spawn` grows it ahead of time instead.
+static READY: Mutex<ReadyQueues> = Mutex::new(ReadyQueues::new());
+
+// One FIFO per priority, with the TSC at which each task was queued. That
+// is pushed back by however long the task overran its poll budget, so it
+// takes that much longer to age past the higher classes.
+struct ReadyQueues {
+    queues: [VecDeque<(TaskId, u64)>; Priority::ALL.len()],
+}
+
+impl ReadyQueues {
+    const fn new() -> Self {
+        ReadyQueues {
+            queues: [const { VecDeque::new() }; Priority::ALL.len()],
+        }
+    }
+
+    fn push(&mut self, priority: Priority, task_id: TaskId, debt: u64) {
+        self.queues[priority as usize].push_back((task_id, time::tsc_now() + debt));
+    }
+
+    // Charges `cycles` to `task_id` if it is queued. Returns whether it was.
+    fn charge(&mut self, priority: Priority, task_id: TaskId, cycles: u64) -> bool {
+        let queue = &mut self.queues[priority as usize];
+        match queue.iter_mut().find(|(id, _)| *id == task_id) {
+            Some((_, queued)) => {
+                *queued += cycles;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The highest priority task, unless a lower one has waited past its
+    // `max_wait`; then the one that has waited longest of those
+    fn pop(&mut self) -> Option<TaskId> {
+        let now = Instant::now();
+        let overdue = Priority::ALL
+            .into_iter()
+            .skip(1)
+            .filter_map(|priority| {
+                let &(_, queued) = self.queues[priority as usize].front()?;
+                let waited = now.duration_since(Instant::from_cycles(queued));
+                (waited > priority.max_wait()).then_some((queued, priority))
+            })
+            .min();
+
+        let queue = match overdue {
+            Some((_, priority)) => &mut self.queues[priority as usize],
+            None => self.queues.iter_mut().find(|queue| !queue.is_empty())?,
+        };
+        queue.pop_front().map(|(task_id, _)| task_id)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+
+    // Ids every queue has room for
+    fn capacity(&self) -> usize {
+        self.queues
+            .iter()
+            .map(|queue| queue.capacity())
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+// The last few tasks to finish, so `ps` can still show them
+const FINISHED_KEPT: usize = 8;
+static FINISHED: Mutex<VecDeque<TaskSnapshot>> = Mutex::new(VecDeque::new());
+
+struct TaskEntry {
+    task: Task,
+    // Made once, so waking and cloning it never allocates
+    waker: Waker,
+    state: Arc<TaskWaker>,
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    priority: Priority,
+    // Set while the task is in READY
+    queued: AtomicBool,
+    // TSC of the last wakeup, 0 if never woken
+    last_woken: AtomicU64,
+    // Overrun cycles not charged yet, see `ReadyQueues`. Only touched with
+    // READY locked.
+    debt: AtomicU64,
+}
+
+impl TaskWaker {
+    fn schedule(&self) {
+        self.last_woken.store(time::tsc_now(), Ordering::Relaxed);
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            interrupts::without_interrupts(|| {
+                let debt = self.debt.swap(0, Ordering::Relaxed);
+                READY.lock().push(self.priority, self.task_id, debt)
+            });
+        }
+    }
+
+    // Charges an overrun of `cycles` now if the task is queued, or else when
+    // it is next woken
+    fn charge(&self, cycles: u64) {
+        interrupts::without_interrupts(|| {
+            if !READY.lock().charge(self.priority, self.task_id, cycles) {
+                self.debt.fetch_add(cycles, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.schedule();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.schedule();
+    }
+}
+
+/// Starts running `future` as a new task. Usable from inside other tasks.
+/// Drop the returned handle to let the task run detached.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_named("task", future)
+}
+
+/// `spawn` with a name for `ps`.
+pub fn spawn_named<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_with_priority(name, Priority::Normal, future)
+}
+
+/// `spawn_named` for a task that isn't of `Priority::Normal`.
+pub fn spawn_with_priority<F>(name: &str, priority: Priority, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (future, handle) = join::joinable(TaskId::new(), future);
+    spawn_task(Task::with_id(handle.id(), name, future).with_priority(priority));
+    handle
+}
+
+/// Yields if the running task has used up its poll budget (see
+/// `Priority::poll_budget`), so long computations can be split up without
+/// yielding on every step.
+pub async fn consume_budget() {
+    let deadline = percpu!(poll_deadline).load(Ordering::Relaxed);
+    if deadline == 0 || time::tsc_now() < deadline {
+        return;
+    }
+
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+fn spawn_task(task: Task) -> TaskId {
+    let task_id = task.id;
+    let state = Arc::new(TaskWaker {
+        task_id,
+        priority: task.priority,
+        queued: AtomicBool::new(false),
+        last_woken: AtomicU64::new(0),
+        debt: AtomicU64::new(0),
+    });
+    let entry = Arc::new(TaskEntry {
+        task,
+        waker: Waker::from(state.clone()),
+        state,
+    });
+
+    let task_count = {
+        let mut tasks = TASKS.lock();
+        tasks.insert(task_id, entry.clone());
+        tasks.len()
+    };
+    // A finished task's waker can still queue its id once, hence twice the tasks
+    reserve_ready(task_count * 2);
+
+    entry.state.schedule();
+    task_id
+}
+
+// Makes room for `capacity` ids in each of READY's queues. The allocation
+// happens with interrupts enabled, the lock is only taken to move the ids over.
+fn reserve_ready(capacity: usize) {
+    while interrupts::without_interrupts(|| READY.lock().capacity()) < capacity {
+        let mut bigger = ReadyQueues {
+            queues: core::array::from_fn(|_| VecDeque::with_capacity(capacity)),
+        };
+        interrupts::without_interrupts(|| {
+            let mut ready = READY.lock();
+            if ready.capacity() < capacity {
+                for (old, new) in ready.queues.iter_mut().zip(bigger.queues.iter_mut()) {
+                    new.extend(old.drain(..));
+                }
+                core::mem::swap(&mut *ready, &mut bigger);
+            }
+        });
+        // The old queues are freed here, with interrupts enabled
+    }
+}
+
+/// Number of tasks that haven't finished yet.
+pub fn task_count() -> usize {
+    TASKS.lock().len()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Being polled right now.
+    Running,
+    /// Woken and waiting for its turn.
+    Ready,
+    /// Waiting for a wakeup.
+    Pending,
+    Done,
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TaskState::Running => "running",
+            TaskState::Ready => "ready",
+            TaskState::Pending => "pending",
+            TaskState::Done => "done",
+        };
+        f.pad(name)
+    }
+}
+
+/// What a task looked like at one point, see `snapshot`.
+#[derive(Debug, Clone)]
+pub struct TaskSnapshot {
+    pub id: TaskId,
+    pub name: String,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub spawned_at: Instant,
+    pub polls: u64,
+    /// Total time spent in `poll`.
+    pub poll_time: Duration,
+    pub last_woken: Option<Instant>,
+    /// Polls that took longer than the priority's budget.
+    pub overruns: u64,
+}
+
+impl TaskEntry {
+    fn snapshot(&self) -> TaskSnapshot {
+        let stats = &self.task.stats;
+        let state = if stats.running.load(Ordering::Relaxed) {
+            TaskState::Running
+        } else if self.state.queued.load(Ordering::Relaxed) {
+            TaskState::Ready
+        } else {
+            TaskState::Pending
+        };
+        let last_woken = self.state.last_woken.load(Ordering::Relaxed);
+
+        TaskSnapshot {
+            id: self.task.id,
+            name: self.task.name.clone(),
+            priority: self.task.priority,
+            state,
+            spawned_at: stats.spawned_at,
+            polls: stats.polls.load(Ordering::Relaxed),
+            poll_time: Duration::from_nanos(time::cycles_to_nanos(
+                stats.poll_cycles.load(Ordering::Relaxed),
+            )),
+            last_woken: (last_woken != 0).then(|| Instant::from_cycles(last_woken)),
+            overruns: stats.overruns.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Every live task, then the last few that finished.
+pub fn snapshot() -> Vec<TaskSnapshot> {
+    let entries: Vec<Arc<TaskEntry>> = TASKS.lock().values().cloned().collect();
+    let mut tasks: Vec<TaskSnapshot> = entries.iter().map(|entry| entry.snapshot()).collect();
+    tasks.extend(FINISHED.lock().iter().cloned());
+    tasks
 }
 
 pub struct Executor;
@@ -20,8 +325,7 @@ impl Executor {
     }
 
     pub fn spawn(&self, task: Task) {
-        let task = Arc::new(task);
-        TASK_QUEUE.push(task).expect("Task queue full");
+        spawn_task(task);
     }
 
     pub fn run(&self) -> ! {
@@ -32,19 +336,70 @@ impl Executor {
     }
 
     fn run_ready_tasks(&self) {
-        while let Some(task) = TASK_QUEUE.pop() {
-            let mut future_slot = task.future.lock();
+        while let Some(task_id) = interrupts::without_interrupts(|| READY.lock().pop()) {
+            // Not there if it finished after being woken
+            let Some(entry) = TASKS.lock().get(&task_id).cloned() else {
+                continue;
+            };
+
+            // Cleared before polling, so a wakeup during the poll queues it again
+            entry.state.queued.store(false, Ordering::Release);
 
-            let waker = Waker::from(task.clone());
-            let mut context = Context::from_waker(&waker);
+            let mut future_slot = entry.task.future.lock();
+            let mut context = Context::from_waker(&entry.waker);
 
-            match future_slot.as_mut().poll(&mut context) {
+            let stats = &entry.task.stats;
+            let budget = entry.task.priority.poll_budget();
+            let cpu = percpu!();
+            cpu.current_task.store(task_id.as_u64(), Ordering::Relaxed);
+            cpu.stats.task_polls.fetch_add(1, Ordering::Relaxed);
+            stats.running.store(true, Ordering::Relaxed);
+            let started = time::tsc_now();
+            cpu.poll_started.store(started, Ordering::Relaxed);
+            let budget_cycles = time::nanos_to_cycles(budget.as_nanos() as u64);
+            cpu.poll_deadline
+                .store(started + budget_cycles, Ordering::Relaxed);
+            let result = future_slot.as_mut().poll(&mut context);
+            cpu.poll_started.store(0, Ordering::Relaxed);
+            cpu.poll_deadline.store(0, Ordering::Relaxed);
+            let cycles = time::tsc_now() - started;
+            stats.poll_cycles.fetch_add(cycles, Ordering::Relaxed);
+            stats.polls.fetch_add(1, Ordering::Relaxed);
+
+            if cycles > budget_cycles {
+                entry.state.charge(cycles - budget_cycles);
+            }
+            let took = Duration::from_nanos(time::cycles_to_nanos(cycles));
+            if took > budget && stats.overruns.fetch_add(1, Ordering::Relaxed) == 0 {
+                log::debug!(
+                    "Task {} ({}) took {} us, over its {} budget of {} us",
+                    task_id.as_u64(),
+                    entry.task.name,
+                    took.as_micros(),
+                    entry.task.priority,
+                    budget.as_micros()
+                );
+            }
+            stats.running.store(false, Ordering::Relaxed);
+            cpu.current_task.store(percpu::NONE, Ordering::Relaxed);
+            drop(future_slot);
+
+            match result {
                 Poll::Ready(()) => {
-                    // Task done, let Arc drop it
+                    // Task done, drop it from the table
+                    TASKS.lock().remove(&task_id);
+
+                    let mut snapshot = entry.snapshot();
+                    snapshot.state = TaskState::Done;
+                    let mut finished = FINISHED.lock();
+                    if finished.len() == FINISHED_KEPT {
+                        finished.pop_front();
+                    }
+                    finished.push_back(snapshot);
                 }
                 Poll::Pending => {
                     // Task is waiting for a waker, do nothing.
-                    // The waker is responsible for pushing it back to TASK_QUEUE
+                    // The waker is responsible for putting it back into READY
                 }
             }
         }
@@ -54,28 +409,18 @@ impl Executor {
         // FAST PATH: Disable interrupts so nothing changes while we check
         interrupts::disable();
 
-        if TASK_QUEUE.is_empty() {
-            // ATOMIC SLEEP: Enable interrupts and halt CPU in one instruction.
-            // This prevents the "lost wakeup" race condition.
-            enable_and_hlt();
+        if READY.lock().is_empty() {
+            if thread::others_ready() {
+                // Let another thread have the CPU instead of halting it
+                interrupts::enable();
+                thread::yield_now();
+            } else {
+                // ATOMIC SLEEP: Enable interrupts and halt CPU in one instruction.
+                // This prevents the "lost wakeup" race condition.
+                enable_and_hlt();
+            }
         } else {
             interrupts::enable();
         }
     }
 }
-
-impl Wake for Task {
-    fn wake(self: Arc<Self>) {
-        self.wake_task();
-    }
-
-    fn wake_by_ref(self: &Arc<Self>) {
-        self.clone().wake_task();
-    }
-}
-
-impl Task {
-    fn wake_task(self: Arc<Self>) {
-        TASK_QUEUE.push(self).expect("Task queue full");
-    }
-}
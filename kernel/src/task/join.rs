@@ -0,0 +1,123 @@
+use super::TaskId;
+use alloc::sync::Arc;
+use core::fmt;
+use core::future::{Future, poll_fn};
+use core::pin::{Pin, pin};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+/// Why a task has no result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    Aborted,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Aborted => write!(f, "task was aborted"),
+        }
+    }
+}
+
+// Shared by a task and its `JoinHandle`
+struct JoinState<T> {
+    result: Mutex<Option<Result<T, JoinError>>>,
+    finished: AtomicBool,
+    aborted: AtomicBool,
+    // Whoever awaits the handle
+    join_waker: AtomicWaker,
+    // The task itself, so `abort` gets it scheduled
+    task_waker: AtomicWaker,
+}
+
+/// Owned permission to wait for a spawned task, returned by `spawn`.
+///
+/// Awaiting it gives the task's output. Dropping it detaches the task,
+/// which then runs on with nobody waiting for it.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Whether the task has finished (or been aborted).
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::Acquire)
+    }
+
+    /// Cancels the task: its future is dropped the next time the executor
+    /// gets to it, and awaiting the handle gives `JoinError::Aborted`.
+    /// Does nothing once the task has finished.
+    pub fn abort(&self) {
+        self.state.aborted.store(true, Ordering::Release);
+        self.state.task_waker.wake();
+    }
+
+    /// Lets the task run on without a handle, same as dropping it.
+    pub fn detach(self) {}
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.state.join_waker.register(cx.waker());
+        match self.state.result.lock().take() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Wraps `future` into what the executor runs for task `id`: it stores the
+/// output for the returned handle and stops early when aborted.
+pub(super) fn joinable<F>(
+    id: TaskId,
+    future: F,
+) -> (
+    impl Future<Output = ()> + Send + 'static,
+    JoinHandle<F::Output>,
+)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let state = Arc::new(JoinState {
+        result: Mutex::new(None),
+        finished: AtomicBool::new(false),
+        aborted: AtomicBool::new(false),
+        join_waker: AtomicWaker::new(),
+        task_waker: AtomicWaker::new(),
+    });
+    let handle = JoinHandle {
+        id,
+        state: state.clone(),
+    };
+
+    let task = async move {
+        // Scoped so the future is dropped before anyone sees the result
+        let result = {
+            let mut future = pin!(future);
+            poll_fn(|cx| {
+                if state.aborted.load(Ordering::Acquire) {
+                    return Poll::Ready(Err(JoinError::Aborted));
+                }
+                state.task_waker.register(cx.waker());
+                future.as_mut().poll(cx).map(Ok)
+            })
+            .await
+        };
+
+        *state.result.lock() = Some(result);
+        state.finished.store(true, Ordering::Release);
+        state.join_waker.wake();
+    };
+    (task, handle)
+}
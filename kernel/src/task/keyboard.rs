@@ -6,6 +6,9 @@ use core::{
 use crossbeam_queue::ArrayQueue;
 use futures_util::stream::Stream;
 use futures_util::task::AtomicWaker;
+use x86_64::instructions::port::Port;
+
+use crate::interrupts::{self, IRQ_KEYBOARD};
 
 // A queue to hold scancodes. We use OnceCell for safe static initialization
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
@@ -13,6 +16,19 @@ static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 // A waker to notify the executor when a new scancode arrives
 static WAKER: AtomicWaker = AtomicWaker::new();
 
+/// Hooks the PS/2 keyboard up to its IRQ line.
+pub fn init() {
+    interrupts::register_irq(IRQ_KEYBOARD, keyboard_irq);
+}
+
+fn keyboard_irq() {
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+
+    // Send the raw scancode to the queue
+    add_scancode(scancode);
+}
+
 /// Called by the interrupt handler to push a scancode
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
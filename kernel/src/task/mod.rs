@@ -1,29 +1,136 @@
-use alloc::boxed::Box;
+use crate::time::{Duration, Instant};
+use alloc::{boxed::Box, string::String};
 use core::fmt;
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use core::{future::Future, pin::Pin};
 use spin::Mutex;
 
 pub mod executor;
+pub mod join;
 pub mod keyboard;
+pub mod sync;
+pub mod timer;
+
+pub use executor::{consume_budget, spawn, spawn_named, spawn_with_priority};
+pub use join::{JoinError, JoinHandle};
+
+/// Scheduling class of a task. Ready tasks of a higher class always go
+/// first, but lower classes still get a turn once they have waited too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Input handling, the shell.
+    Interactive,
+    Normal,
+    /// Animations and batch work.
+    Background,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [
+        Priority::Interactive,
+        Priority::Normal,
+        Priority::Background,
+    ];
+
+    /// How long one poll of such a task should take at most. Lower classes
+    /// get less, so they can't hold up input for long. A task that takes
+    /// longer waits that much longer before it goes ahead of higher classes.
+    pub fn poll_budget(&self) -> Duration {
+        match self {
+            Priority::Interactive => Duration::from_millis(10),
+            Priority::Normal => Duration::from_millis(5),
+            Priority::Background => Duration::from_millis(2),
+        }
+    }
+
+    /// How long such a task may sit in the ready queue before it goes
+    /// ahead of the higher classes.
+    pub fn max_wait(&self) -> Duration {
+        match self {
+            Priority::Interactive => Duration::ZERO,
+            Priority::Normal => Duration::from_millis(50),
+            Priority::Background => Duration::from_millis(200),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Priority::Interactive => "interactive",
+            Priority::Normal => "normal",
+            Priority::Background => "background",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Priority> {
+        Priority::ALL
+            .into_iter()
+            .find(|priority| priority.name() == name)
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
 
 pub struct Task {
     pub id: TaskId,
+    pub name: String,
+    pub priority: Priority,
     pub future: Mutex<Pin<Box<dyn Future<Output = ()> + Send + 'static>>>,
+    stats: TaskStats,
+}
+
+// Kept up to date by the executor
+struct TaskStats {
+    spawned_at: Instant,
+    running: AtomicBool,
+    polls: AtomicU64,
+    // Total TSC cycles spent in `poll`
+    poll_cycles: AtomicU64,
+    // Polls that took longer than the priority's budget
+    overruns: AtomicU64,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Task::named("task", future)
+    }
+
+    pub fn named(name: &str, future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Task::with_id(TaskId::new(), name, future)
+    }
+
+    fn with_id(id: TaskId, name: &str, future: impl Future<Output = ()> + Send + 'static) -> Task {
         Task {
-            id: TaskId::new(),
+            id,
+            name: String::from(name),
+            priority: Priority::Normal,
             future: Mutex::new(Box::pin(future)),
+            stats: TaskStats {
+                spawned_at: Instant::now(),
+                running: AtomicBool::new(false),
+                polls: AtomicU64::new(0),
+                poll_cycles: AtomicU64::new(0),
+                overruns: AtomicU64::new(0),
+            },
         }
     }
+
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.priority = priority;
+        self
+    }
 }
 
 impl fmt::Debug for Task {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Task").field("id", &self.id).finish()
+        f.debug_struct("Task")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("priority", &self.priority)
+            .finish()
     }
 }
 
@@ -35,4 +142,8 @@ impl TaskId {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
 }
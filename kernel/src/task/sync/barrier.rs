@@ -0,0 +1,76 @@
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+use spin::Mutex;
+
+/// Lets a fixed number of tasks wait until all of them have arrived.
+/// Can be reused; each round starts once the previous one is complete.
+pub struct Barrier {
+    parties: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    arrived: usize,
+    // Completed rounds
+    generation: u64,
+    waiters: Vec<Waker>,
+}
+
+/// Returned to every task leaving the barrier.
+#[derive(Debug, Clone, Copy)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// True for exactly one task per round, the last to arrive.
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+impl Barrier {
+    pub const fn new(parties: usize) -> Self {
+        Barrier {
+            parties: if parties == 0 { 1 } else { parties },
+            state: Mutex::new(State {
+                arrived: 0,
+                generation: 0,
+                waiters: Vec::new(),
+            }),
+        }
+    }
+
+    /// Waits for the other tasks. A task dropping this future after its
+    /// first poll still counts as arrived.
+    pub async fn wait(&self) -> BarrierWaitResult {
+        let generation = {
+            let mut state = self.state.lock();
+            state.arrived += 1;
+            if state.arrived == self.parties {
+                state.arrived = 0;
+                state.generation += 1;
+                for waker in state.waiters.drain(..) {
+                    waker.wake();
+                }
+                return BarrierWaitResult(true);
+            }
+            state.generation
+        };
+
+        poll_fn(|cx| {
+            let mut state = self.state.lock();
+            if state.generation != generation {
+                return Poll::Ready(BarrierWaitResult(false));
+            }
+            if !state
+                .waiters
+                .iter()
+                .any(|waker| waker.will_wake(cx.waker()))
+            {
+                state.waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
@@ -0,0 +1,63 @@
+// Async versions of the usual locks and channels, for sharing state between tasks.
+//
+// A `spin::Mutex` held across an `.await` stalls the whole executor thread;
+// these park the waiting task's `Waker` instead. None of them may be used
+// from interrupt handlers.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Waker;
+use spin::Mutex;
+
+mod barrier;
+mod mutex;
+mod notify;
+mod rwlock;
+mod semaphore;
+
+pub mod mpsc;
+pub mod oneshot;
+
+pub use barrier::{Barrier, BarrierWaitResult};
+pub use mutex::{AsyncMutex, AsyncMutexGuard};
+pub use notify::Notify;
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{Semaphore, SemaphorePermit};
+
+// A task parked in a wait queue. Shared by the queue and the waiting future,
+// so a future that is dropped early can take itself out again.
+struct Waiter {
+    waker: Mutex<Option<Waker>>,
+    woken: AtomicBool,
+    // What it waits for (semaphore permits)
+    wanted: usize,
+}
+
+impl Waiter {
+    fn new(wanted: usize, waker: &Waker) -> Arc<Waiter> {
+        Arc::new(Waiter {
+            waker: Mutex::new(Some(waker.clone())),
+            woken: AtomicBool::new(false),
+            wanted,
+        })
+    }
+
+    // Keeps the newest waker of the task, which may have moved
+    fn update(&self, waker: &Waker) {
+        let mut slot = self.waker.lock();
+        if !slot.as_ref().is_some_and(|old| old.will_wake(waker)) {
+            *slot = Some(waker.clone());
+        }
+    }
+
+    fn wake(&self) {
+        self.woken.store(true, Ordering::Release);
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+
+    fn is_woken(&self) -> bool {
+        self.woken.load(Ordering::Acquire)
+    }
+}
@@ -0,0 +1,104 @@
+// An unbounded channel with any number of senders and one receiver.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+use spin::Mutex;
+
+struct Inner<T> {
+    queue: VecDeque<T>,
+    receiver_waker: Option<Waker>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+pub struct Sender<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+pub struct Receiver<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Mutex::new(Inner {
+        queue: VecDeque::new(),
+        receiver_waker: None,
+        senders: 1,
+        receiver_alive: true,
+    }));
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Queues `value`, or gives it back if the receiver is gone.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let mut inner = self.inner.lock();
+        if !inner.receiver_alive {
+            return Err(value);
+        }
+        inner.queue.push_back(value);
+        if let Some(waker) = inner.receiver_waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.inner.lock().senders += 1;
+        Sender {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock();
+        inner.senders -= 1;
+        // The receiver has to learn that nothing more is coming
+        if inner.senders == 0
+            && let Some(waker) = inner.receiver_waker.take()
+        {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// The next value, or `None` once every sender is gone and the queue is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| {
+            let mut inner = self.inner.lock();
+            if let Some(value) = inner.queue.pop_front() {
+                Poll::Ready(Some(value))
+            } else if inner.senders == 0 {
+                Poll::Ready(None)
+            } else {
+                inner.receiver_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.inner.lock().queue.pop_front()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock();
+        inner.receiver_alive = false;
+        inner.queue.clear();
+    }
+}
@@ -0,0 +1,62 @@
+use super::{Semaphore, SemaphorePermit};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+/// A mutex whose `lock` parks the task instead of spinning.
+/// Can be held across `.await`.
+pub struct AsyncMutex<T: ?Sized> {
+    semaphore: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for AsyncMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    pub const fn new(data: T) -> Self {
+        AsyncMutex {
+            semaphore: Semaphore::new(1),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> AsyncMutex<T> {
+    pub async fn lock(&self) -> AsyncMutexGuard<'_, T> {
+        AsyncMutexGuard {
+            _permit: self.semaphore.acquire().await,
+            mutex: self,
+        }
+    }
+
+    /// Takes the lock if it is free. Also usable outside of tasks (during boot).
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        Some(AsyncMutexGuard {
+            _permit: self.semaphore.try_acquire()?,
+            mutex: self,
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.semaphore.available_permits() == 0
+    }
+}
+
+pub struct AsyncMutexGuard<'a, T: ?Sized> {
+    _permit: SemaphorePermit<'a>,
+    mutex: &'a AsyncMutex<T>,
+}
+
+impl<T: ?Sized> Deref for AsyncMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for AsyncMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
@@ -0,0 +1,107 @@
+use super::Waiter;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use spin::Mutex;
+
+/// Lets one task tell others that something happened (an event).
+pub struct Notify {
+    state: Mutex<State>,
+}
+
+struct State {
+    // A `notify_one` nobody was waiting for, kept for the next `notified`
+    permit: bool,
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: Mutex::new(State {
+                permit: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Wakes the task that has waited longest, or the next one to wait
+    /// if there is none right now.
+    pub fn notify_one(&self) {
+        let mut state = self.state.lock();
+        match state.waiters.pop_front() {
+            Some(waiter) => waiter.wake(),
+            None => state.permit = true,
+        }
+    }
+
+    /// Wakes every task waiting right now.
+    pub fn notify_waiters(&self) {
+        for waiter in self.state.lock().waiters.drain(..) {
+            waiter.wake();
+        }
+    }
+
+    /// Waits for a notification.
+    pub async fn notified(&self) {
+        Notified {
+            notify: self,
+            waiter: None,
+        }
+        .await
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Notified<'a> {
+    notify: &'a Notify,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(waiter) = &self.waiter {
+            if waiter.is_woken() {
+                self.waiter = None;
+                return Poll::Ready(());
+            }
+            waiter.update(cx.waker());
+            return Poll::Pending;
+        }
+
+        let mut state = self.notify.state.lock();
+        if state.permit {
+            state.permit = false;
+            return Poll::Ready(());
+        }
+        let waiter = Waiter::new(1, cx.waker());
+        state.waiters.push_back(waiter.clone());
+        drop(state);
+        self.waiter = Some(waiter);
+        Poll::Pending
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let Some(waiter) = self.waiter.take() else {
+            return;
+        };
+        if waiter.is_woken() {
+            // Woken but gone before it noticed, hand the notification on
+            self.notify.notify_one();
+        } else {
+            let mut state = self.notify.state.lock();
+            state.waiters.retain(|other| !Arc::ptr_eq(other, &waiter));
+        }
+    }
+}
@@ -0,0 +1,106 @@
+// A channel for sending a single value, e.g. a reply.
+
+use alloc::sync::Arc;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// The sender was dropped without sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sender dropped without sending")
+    }
+}
+
+struct Inner<T> {
+    value: Option<T>,
+    receiver_waker: Option<Waker>,
+    // Set when either side is dropped
+    closed: bool,
+}
+
+pub struct Sender<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+/// Awaiting it gives the value, or `RecvError` if the sender went away.
+pub struct Receiver<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Mutex::new(Inner {
+        value: None,
+        receiver_waker: None,
+        closed: false,
+    }));
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Hands `value` to the receiver, or back if the receiver is gone.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let mut inner = self.inner.lock();
+        if inner.closed {
+            return Err(value);
+        }
+        inner.value = Some(value);
+        if let Some(waker) = inner.receiver_waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.lock().closed
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock();
+        inner.closed = true;
+        if let Some(waker) = inner.receiver_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// The value, if it has been sent already.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.inner.lock().value.take()
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.inner.lock();
+        if let Some(value) = inner.value.take() {
+            Poll::Ready(Ok(value))
+        } else if inner.closed {
+            Poll::Ready(Err(RecvError))
+        } else {
+            inner.receiver_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.lock().closed = true;
+    }
+}
@@ -0,0 +1,87 @@
+use super::{Semaphore, SemaphorePermit};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+// A reader takes one permit, a writer all of them
+const MAX_READERS: usize = 1 << 16;
+
+/// Many readers or one writer, parking tasks that have to wait.
+/// Lock requests are served in order, so writers don't starve.
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> Self {
+        RwLock {
+            semaphore: Semaphore::new(MAX_READERS),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        RwLockReadGuard {
+            _permit: self.semaphore.acquire().await,
+            lock: self,
+        }
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        RwLockWriteGuard {
+            _permit: self.semaphore.acquire_many(MAX_READERS).await,
+            lock: self,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        Some(RwLockReadGuard {
+            _permit: self.semaphore.try_acquire()?,
+            lock: self,
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        Some(RwLockWriteGuard {
+            _permit: self.semaphore.try_acquire_many(MAX_READERS)?,
+            lock: self,
+        })
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    _permit: SemaphorePermit<'a>,
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    _permit: SemaphorePermit<'a>,
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
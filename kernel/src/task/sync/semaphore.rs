@@ -0,0 +1,160 @@
+use super::Waiter;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use spin::Mutex;
+
+/// Counts permits; acquiring waits until enough are free.
+///
+/// Waiters are served first come, first served, so a task asking for many
+/// permits isn't overtaken forever by tasks asking for one.
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+struct State {
+    permits: usize,
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+impl State {
+    // Hands permits to the waiters at the front, as far as they go
+    fn wake_waiters(&mut self) {
+        while let Some(front) = self.waiters.front() {
+            if front.wanted > self.permits {
+                break;
+            }
+            self.permits -= front.wanted;
+            front.wake();
+            self.waiters.pop_front();
+        }
+    }
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Mutex::new(State {
+                permits,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    pub fn add_permits(&self, count: usize) {
+        let mut state = self.state.lock();
+        state.permits += count;
+        state.wake_waiters();
+    }
+
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.acquire_many(1).await
+    }
+
+    pub async fn acquire_many(&self, count: usize) -> SemaphorePermit<'_> {
+        Acquire {
+            semaphore: self,
+            count,
+            waiter: None,
+        }
+        .await;
+        SemaphorePermit {
+            semaphore: self,
+            count,
+        }
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    pub fn try_acquire_many(&self, count: usize) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock();
+        // Don't jump the queue
+        if !state.waiters.is_empty() || state.permits < count {
+            return None;
+        }
+        state.permits -= count;
+        Some(SemaphorePermit {
+            semaphore: self,
+            count,
+        })
+    }
+}
+
+/// Permits taken from a `Semaphore`, given back when dropped.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    count: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permits taken for good.
+    pub fn forget(mut self) {
+        self.count = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.count > 0 {
+            self.semaphore.add_permits(self.count);
+        }
+    }
+}
+
+struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    count: usize,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl Future for Acquire<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(waiter) = &self.waiter {
+            // The permits were taken for us by `wake_waiters`
+            if waiter.is_woken() {
+                self.waiter = None;
+                return Poll::Ready(());
+            }
+            waiter.update(cx.waker());
+            return Poll::Pending;
+        }
+
+        let mut state = self.semaphore.state.lock();
+        if state.waiters.is_empty() && state.permits >= self.count {
+            state.permits -= self.count;
+            return Poll::Ready(());
+        }
+        let waiter = Waiter::new(self.count, cx.waker());
+        state.waiters.push_back(waiter.clone());
+        drop(state);
+        self.waiter = Some(waiter);
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let Some(waiter) = self.waiter.take() else {
+            return;
+        };
+        let mut state = self.semaphore.state.lock();
+        if waiter.is_woken() {
+            // Granted but never picked up, pass the permits on
+            state.permits += self.count;
+        } else {
+            state.waiters.retain(|other| !Arc::ptr_eq(other, &waiter));
+        }
+        // Whoever queued behind us may fit now
+        state.wake_waiters();
+    }
+}
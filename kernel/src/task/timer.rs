@@ -0,0 +1,106 @@
+use crate::interrupts::{self, IRQ_TIMER};
+use crate::time::{Duration, Instant};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Tasks that can sleep at the same time.
+pub const MAX_SLEEPERS: usize = 64;
+
+// Filled by sleeping tasks and emptied by the timer IRQ. Fixed slots, so
+// nothing allocates with interrupts disabled. Wakeups come with the PIT
+// tick, so a sleep can run ~55 ms long.
+static SLEEPERS: Mutex<[Option<Sleeper>; MAX_SLEEPERS]> =
+    Mutex::new([const { None }; MAX_SLEEPERS]);
+
+struct Sleeper {
+    // Tells a slot's owner apart from a later one
+    id: u64,
+    deadline: Instant,
+    waker: Waker,
+}
+
+/// Hooks the sleepers up to the timer.
+pub fn init() {
+    interrupts::register_irq(IRQ_TIMER, wake_sleepers);
+}
+
+/// Completes once `duration` has passed.
+pub fn sleep(duration: Duration) -> Sleep {
+    let deadline = Instant::now()
+        .checked_add(duration)
+        .unwrap_or(Instant::from_cycles(u64::MAX));
+    sleep_until(deadline)
+}
+
+/// Completes once `deadline` has passed.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    Sleep {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        deadline,
+    }
+}
+
+pub struct Sleep {
+    id: u64,
+    deadline: Instant,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            // Our slot, if the timer hasn't woken us since the last poll
+            let slot = match sleepers
+                .iter()
+                .position(|slot| slot.as_ref().is_some_and(|sleeper| sleeper.id == self.id))
+            {
+                Some(index) => &mut sleepers[index],
+                None => sleepers
+                    .iter_mut()
+                    .find(|slot| slot.is_none())
+                    .expect("too many sleeping tasks"),
+            };
+            *slot = Some(Sleeper {
+                id: self.id,
+                deadline: self.deadline,
+                waker: cx.waker().clone(),
+            });
+        });
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        without_interrupts(|| {
+            for slot in SLEEPERS.lock().iter_mut() {
+                if slot.as_ref().is_some_and(|sleeper| sleeper.id == self.id) {
+                    *slot = None;
+                }
+            }
+        });
+    }
+}
+
+// Runs on every timer IRQ
+fn wake_sleepers() {
+    let now = Instant::now();
+    for slot in SLEEPERS.lock().iter_mut() {
+        if slot.as_ref().is_some_and(|sleeper| sleeper.deadline <= now)
+            && let Some(sleeper) = slot.take()
+        {
+            sleeper.waker.wake();
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use crate::interrupts::{self, IRQ_TIMER};
+use crate::memory::{self, AddressSpace};
+use crate::percpu::PerCpu;
+use crate::time::{self, Duration, Instant};
+use crate::{gdt, percpu};
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use core::arch::global_asm;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+use spin::{Mutex, Once};
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts::{self as cpu_interrupts, enable_and_hlt};
+use x86_64::registers::control::Cr3;
+
+// Preemptive kernel threads, scheduled round robin on the BSP (the APs stay
+// parked). The timer IRQ counts down the running thread's quantum; when it
+// runs out, `dispatch_irq` switches to the next ready thread on its way out.
+//
+// Nothing on the switch path allocates or frees: it can run in interrupt
+// context while the interrupted thread holds the allocator lock. Exited
+// threads are freed by the idle thread instead.
+
+/// Kernel stack of every spawned thread.
+pub const STACK_SIZE: usize = 4096 * 16;
+/// Threads that can exist at once.
+pub const MAX_THREADS: usize = 64;
+/// Timer ticks (PIT, ~55 ms each) a thread runs before it is preempted.
+pub const QUANTUM_TICKS: u64 = 2;
+
+global_asm!(include_str!("context_switch.asm"));
+
+unsafe extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    Exited,
+}
+
+impl State {
+    fn from_u8(value: u8) -> State {
+        match value {
+            0 => State::Ready,
+            1 => State::Running,
+            _ => State::Exited,
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            State::Ready => "ready",
+            State::Running => "running",
+            State::Exited => "exited",
+        };
+        f.pad(name)
+    }
+}
+
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    state: AtomicU8,
+    // Saved stack pointer while switched out, written by `switch_context`
+    rsp: UnsafeCell<u64>,
+    // None for the boot thread, which keeps the bootloader's stack
+    stack: Option<Box<[u8]>>,
+    entry: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    switches: AtomicU64,
+    // The user program's page tables, None for kernel-only threads
+    address_space: Option<Arc<AddressSpace>>,
+    // PID of that program, `percpu::NONE` for kernel-only threads
+    process: u64,
+    // The executor's poll while switched out, see `SavedPoll`
+    poll: UnsafeCell<SavedPoll>,
+}
+
+// `rsp` and `poll` are only touched by the scheduler, with interrupts disabled
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> State {
+        State::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    /// How often this thread was switched to.
+    pub fn switches(&self) -> u64 {
+        self.switches.load(Ordering::Relaxed)
+    }
+
+    pub fn stack_size(&self) -> usize {
+        self.stack.as_ref().map_or(0, |stack| stack.len())
+    }
+}
+
+// What the executor is polling on a thread (`current_task`, `poll_started`
+// and `poll_deadline` of the CPU) while the thread is switched out. The
+// watchdog reads the CPU's copy, which must only describe the running thread.
+#[derive(Clone, Copy)]
+struct SavedPoll {
+    task: u64,
+    // Cycles the poll had run and had left of its budget, None outside a poll
+    progress: Option<(u64, u64)>,
+}
+
+impl SavedPoll {
+    const NONE: SavedPoll = SavedPoll {
+        task: percpu::NONE,
+        progress: None,
+    };
+
+    fn save(cpu: &PerCpu) -> Self {
+        let now = time::tsc_now();
+        let started = cpu.poll_started.load(Ordering::Relaxed);
+        let deadline = cpu.poll_deadline.load(Ordering::Relaxed);
+        SavedPoll {
+            task: cpu.current_task.load(Ordering::Relaxed),
+            progress: (started != 0)
+                .then(|| (now.saturating_sub(started), deadline.saturating_sub(now))),
+        }
+    }
+
+    // Time spent switched out counts neither as stuck nor against the budget
+    fn restore(&self, cpu: &PerCpu) {
+        let now = time::tsc_now();
+        let (started, deadline) = match self.progress {
+            Some((ran, left)) => (now - ran, now + left),
+            None => (0, 0),
+        };
+        cpu.current_task.store(self.task, Ordering::Relaxed);
+        cpu.poll_started.store(started, Ordering::Relaxed);
+        cpu.poll_deadline.store(deadline, Ordering::Relaxed);
+    }
+}
+
+lazy_static! {
+    static ref READY: ArrayQueue<Arc<Thread>> = ArrayQueue::new(MAX_THREADS);
+    static ref EXITED: ArrayQueue<Arc<Thread>> = ArrayQueue::new(MAX_THREADS);
+}
+
+// Every thread that hasn't been freed yet, for `threads`
+static THREADS: Mutex<Vec<Arc<Thread>>> = Mutex::new(Vec::new());
+static CURRENT: Mutex<Option<Arc<Thread>>> = Mutex::new(None);
+// Runs when nothing else is ready, never sits in READY
+static IDLE: Once<Arc<Thread>> = Once::new();
+
+static TICKS_LEFT: AtomicU64 = AtomicU64::new(QUANTUM_TICKS);
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+/// Turns the calling (boot) code into the idle thread and starts preempting.
+/// Needs the heap.
+pub fn init() {
+    let idle = Arc::new(Thread {
+        id: ThreadId::new(),
+        name: String::from("idle"),
+        state: AtomicU8::new(State::Running as u8),
+        rsp: UnsafeCell::new(0),
+        stack: None,
+        entry: Mutex::new(None),
+        switches: AtomicU64::new(0),
+        address_space: None,
+        process: percpu::NONE,
+        poll: UnsafeCell::new(SavedPoll::NONE),
+    });
+    THREADS.lock().push(idle.clone());
+    *CURRENT.lock() = Some(idle.clone());
+    IDLE.call_once(|| idle);
+
+    interrupts::register_irq(IRQ_TIMER, timer_tick);
+}
+
+/// Starts a kernel thread running `entry`. It exits when `entry` returns.
+pub fn spawn(name: &str, entry: impl FnOnce() + Send + 'static) -> ThreadId {
+    spawn_inner(name, None, percpu::NONE, entry)
+}
+
+/// Like `spawn`, but the thread runs process `pid` with `address_space`
+/// loaded.
+pub fn spawn_user(
+    name: &str,
+    pid: u64,
+    address_space: Arc<AddressSpace>,
+    entry: impl FnOnce() + Send + 'static,
+) -> ThreadId {
+    spawn_inner(name, Some(address_space), pid, entry)
+}
+
+fn spawn_inner(
+    name: &str,
+    address_space: Option<Arc<AddressSpace>>,
+    process: u64,
+    entry: impl FnOnce() + Send + 'static,
+) -> ThreadId {
+    reap();
+
+    let stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+    let top = stack_top(&stack);
+
+    // What `switch_context` pops: r15, r14, r13, r12, rbx, rbp, then it returns
+    // into `thread_start`. Above that, a null return address for `thread_start`
+    // keeps the stack aligned like after a call and ends backtraces.
+    let frame: [u64; 8] = [0, 0, 0, 0, 0, 0, thread_start as usize as u64, 0];
+    let rsp = top - size_of_val(&frame) as u64;
+    unsafe { (rsp as *mut [u64; 8]).write(frame) };
+
+    let thread = Arc::new(Thread {
+        id: ThreadId::new(),
+        name: String::from(name),
+        state: AtomicU8::new(State::Ready as u8),
+        rsp: UnsafeCell::new(rsp),
+        stack: Some(stack),
+        entry: Mutex::new(Some(Box::new(entry))),
+        switches: AtomicU64::new(0),
+        address_space,
+        process,
+        poll: UnsafeCell::new(SavedPoll::NONE),
+    });
+    let id = thread.id;
+
+    THREADS.lock().push(thread.clone());
+    cpu_interrupts::without_interrupts(|| push(&READY, thread));
+    id
+}
+
+/// The running thread.
+pub fn current() -> Option<Arc<Thread>> {
+    cpu_interrupts::without_interrupts(|| CURRENT.lock().clone())
+}
+
+/// Every live thread, in the order they were created.
+pub fn threads() -> Vec<Arc<Thread>> {
+    THREADS.lock().clone()
+}
+
+/// Whether a thread other than the running one is waiting for the CPU.
+pub fn others_ready() -> bool {
+    !READY.is_empty()
+}
+
+/// Gives the rest of this quantum to the next ready thread, if there is one.
+pub fn yield_now() {
+    cpu_interrupts::without_interrupts(schedule);
+}
+
+/// Lets other threads run, or halts until the next interrupt if none is
+/// ready. For threads waiting on something an interrupt or another thread
+/// brings.
+pub fn pause() {
+    // Same race as in the executor: don't sleep through a wakeup
+    cpu_interrupts::disable();
+    if READY.is_empty() {
+        enable_and_hlt();
+    } else {
+        cpu_interrupts::enable();
+        yield_now();
+    }
+}
+
+/// Blocks the calling thread for at least `duration`.
+pub fn sleep(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        pause();
+    }
+}
+
+/// Ends the calling thread.
+pub fn exit() -> ! {
+    cpu_interrupts::disable();
+    if let Some(current) = CURRENT.lock().as_ref() {
+        assert!(!Arc::ptr_eq(current, idle()), "the idle thread can't exit");
+        current.set_state(State::Exited);
+    }
+    schedule();
+    unreachable!("exited thread was resumed");
+}
+
+/// Runs the idle thread. Call from the boot code after `init`.
+pub fn idle_loop() -> ! {
+    loop {
+        reap();
+        pause();
+    }
+}
+
+/// Called by `dispatch_irq` after the EOI: switches away if the quantum ran out.
+pub fn preempt_if_needed() {
+    if NEED_RESCHED.swap(false, Ordering::Relaxed) {
+        schedule();
+    }
+}
+
+// Runs on every timer IRQ
+fn timer_tick() {
+    if TICKS_LEFT.fetch_sub(1, Ordering::Relaxed) <= 1 {
+        TICKS_LEFT.store(QUANTUM_TICKS, Ordering::Relaxed);
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    }
+}
+
+fn push(queue: &ArrayQueue<Arc<Thread>>, thread: Arc<Thread>) {
+    if queue.push(thread).is_err() {
+        panic!("too many threads");
+    }
+}
+
+fn idle() -> &'static Arc<Thread> {
+    IDLE.get().expect("threads not initialized")
+}
+
+// Switches to the next ready thread (or idle). Interrupts must be disabled.
+fn schedule() {
+    let mut current = CURRENT.lock();
+    let Some(prev) = current.clone() else {
+        return;
+    };
+    let prev_is_idle = Arc::ptr_eq(&prev, idle());
+
+    let next = match READY.pop() {
+        Some(next) => next,
+        // Keep running, unless there's nothing to keep running
+        None if prev.state() == State::Running || prev_is_idle => return,
+        None => idle().clone(),
+    };
+
+    match prev.state() {
+        State::Exited => push(&EXITED, prev.clone()),
+        _ if prev_is_idle => prev.set_state(State::Ready),
+        _ => {
+            prev.set_state(State::Ready);
+            push(&READY, prev.clone());
+        }
+    }
+    next.set_state(State::Running);
+    next.switches.fetch_add(1, Ordering::Relaxed);
+    *current = Some(next.clone());
+
+    let page_table = next
+        .address_space
+        .as_ref()
+        .map_or_else(memory::kernel_page_table, |space| space.page_table());
+    let (active, flags) = Cr3::read();
+    if active != page_table {
+        unsafe { Cr3::write(page_table, flags) };
+    }
+
+    // Where syscalls and interrupts from ring 3 enter the kernel
+    if let Some(stack) = next.stack.as_deref() {
+        let top = stack_top(stack);
+        gdt::set_kernel_stack(VirtAddr::new(top));
+        percpu!(kernel_stack_top).store(top, Ordering::Relaxed);
+    }
+    let cpu = percpu!();
+    cpu.current_process.store(next.process, Ordering::Relaxed);
+    unsafe { *prev.poll.get() = SavedPoll::save(cpu) };
+    unsafe { (*next.poll.get()).restore(cpu) };
+
+    TICKS_LEFT.store(QUANTUM_TICKS, Ordering::Relaxed);
+    percpu!(stats)
+        .context_switches
+        .fetch_add(1, Ordering::Relaxed);
+
+    let old_rsp = prev.rsp.get();
+    let new_rsp = unsafe { *next.rsp.get() };
+    // READY, EXITED or CURRENT still hold both, so none of this frees anything
+    drop(current);
+    drop(prev);
+    drop(next);
+
+    unsafe { switch_context(old_rsp, new_rsp) };
+}
+
+fn stack_top(stack: &[u8]) -> u64 {
+    (stack.as_ptr() as u64 + stack.len() as u64) & !0xF
+}
+
+// Where new threads start, with interrupts still disabled from `schedule`
+extern "C" fn thread_start() -> ! {
+    cpu_interrupts::enable();
+
+    let entry = current().and_then(|thread| thread.entry.lock().take());
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+// Frees exited threads (their stacks). Never called on the switch path.
+fn reap() {
+    while let Some(thread) = cpu_interrupts::without_interrupts(|| EXITED.pop()) {
+        THREADS.lock().retain(|other| !Arc::ptr_eq(other, &thread));
+    }
+}
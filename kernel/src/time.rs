@@ -0,0 +1,199 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+pub use core::time::Duration;
+
+/// Input frequency of the PIT in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+// Calibration window: 1193182 / 100 = 10ms per PIT one-shot
+const CALIBRATION_DIVISOR: u16 = (PIT_FREQUENCY / 100) as u16;
+const CALIBRATION_ROUNDS: usize = 3;
+// Gives up on a round after this many TSC cycles: a 10ms window, even at
+// 10 GHz, is a tenth of it, so the PIT isn't there or its gate doesn't work
+const CALIBRATION_TIMEOUT_CYCLES: u64 = 1_000_000_000;
+// What the TSC is taken to run at when neither the PIT nor CPUID knows
+const FALLBACK_TSC_FREQUENCY: u64 = 2_000_000_000;
+
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static TSC_AT_BOOT: AtomicU64 = AtomicU64::new(0);
+static TSC_INVARIANT: AtomicBool = AtomicBool::new(false);
+
+/// Detects an invariant TSC and calibrates its frequency against PIT channel 2,
+/// or takes it from CPUID if the PIT doesn't answer.
+/// Must run before interrupts start using `Instant`.
+pub fn init() {
+    TSC_AT_BOOT.store(tsc_now(), Ordering::Relaxed);
+    TSC_INVARIANT.store(detect_invariant_tsc(), Ordering::Relaxed);
+
+    // Take the fastest of a few rounds; slower rounds were disturbed by something
+    let mut best = u64::MAX;
+    for _ in 0..CALIBRATION_ROUNDS {
+        match calibrate_round() {
+            Some(cycles) => best = best.min(cycles),
+            None => break,
+        }
+    }
+
+    let frequency = if best != u64::MAX {
+        best * (PIT_FREQUENCY / CALIBRATION_DIVISOR as u64)
+    } else if let Some(frequency) = cpuid_tsc_frequency() {
+        log::warn!("PIT channel 2 doesn't count, TSC frequency taken from CPUID");
+        frequency
+    } else {
+        log::warn!(
+            "Can't calibrate the TSC, assuming {} MHz",
+            FALLBACK_TSC_FREQUENCY / 1_000_000
+        );
+        FALLBACK_TSC_FREQUENCY
+    };
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+}
+
+/// Reads the raw timestamp counter.
+#[inline(always)]
+pub fn tsc_now() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Calibrated TSC frequency in Hz (0 before `init`).
+pub fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Whether CPUID reports an invariant TSC (constant rate across P/C-states).
+pub fn tsc_is_invariant() -> bool {
+    TSC_INVARIANT.load(Ordering::Relaxed)
+}
+
+/// Converts a number of TSC cycles into nanoseconds.
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    let hz = tsc_frequency();
+    if hz == 0 {
+        return 0;
+    }
+    ((cycles as u128 * 1_000_000_000) / hz as u128) as u64
+}
+
+/// Converts nanoseconds into TSC cycles.
+pub fn nanos_to_cycles(nanos: u64) -> u64 {
+    ((nanos as u128 * tsc_frequency() as u128) / 1_000_000_000) as u64
+}
+
+/// Time elapsed since `init` ran.
+pub fn uptime() -> Duration {
+    Instant(TSC_AT_BOOT.load(Ordering::Relaxed)).elapsed()
+}
+
+/// How long after boot `instant` was taken. Zero for anything before `init`.
+pub fn uptime_at(instant: Instant) -> Duration {
+    instant.duration_since(Instant(TSC_AT_BOOT.load(Ordering::Relaxed)))
+}
+
+/// Spins until `duration` has passed. Only for short hardware delays.
+pub fn busy_wait(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        core::hint::spin_loop();
+    }
+}
+
+/// A point in time measured with the TSC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(tsc_now())
+    }
+
+    pub const fn from_cycles(cycles: u64) -> Self {
+        Instant(cycles)
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.0
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Returns zero if `earlier` is actually later than `self`.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(cycles_to_nanos(self.0.saturating_sub(earlier.0)))
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0
+            .checked_add(nanos_to_cycles(duration.as_nanos() as u64))
+            .map(Instant)
+    }
+}
+
+fn detect_invariant_tsc() -> bool {
+    // Leaf 0x8000_0007 EDX bit 8 = Invariant TSC
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended < 0x8000_0007 {
+        return false;
+    }
+    unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// Counts TSC cycles during one 10ms PIT channel 2 one-shot. None if the
+/// one-shot never ends.
+fn calibrate_round() -> Option<u64> {
+    let mut speaker_port: Port<u8> = Port::new(0x61);
+    let mut command_port: Port<u8> = Port::new(0x43);
+    let mut channel2_port: Port<u8> = Port::new(0x42);
+
+    unsafe {
+        // Gate high (bit 0), speaker off (bit 1)
+        let speaker = speaker_port.read();
+        speaker_port.write((speaker & !0x02) | 0x01);
+
+        // 0xB0 = 1011 0000
+        // Channel 2 | Access Lo/Hi byte | Mode 0 (Interrupt on terminal count) | Binary
+        command_port.write(0xB0);
+        channel2_port.write(CALIBRATION_DIVISOR as u8);
+        channel2_port.write((CALIBRATION_DIVISOR >> 8) as u8);
+
+        // Restart the count by pulsing the gate
+        let speaker = speaker_port.read();
+        speaker_port.write(speaker & !0x01);
+        speaker_port.write(speaker | 0x01);
+
+        let start = tsc_now();
+        // Bit 5 mirrors OUT2, which goes high on terminal count
+        while speaker_port.read() & 0x20 == 0 {
+            if tsc_now() - start > CALIBRATION_TIMEOUT_CYCLES {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+        Some(tsc_now() - start)
+    }
+}
+
+/// TSC frequency as CPUID reports it: leaf 0x15 (crystal clock and TSC
+/// ratio), else leaf 0x16 (base frequency). The HPET would need ACPI and
+/// the physical memory mapping, neither of which is up this early.
+fn cpuid_tsc_frequency() -> Option<u64> {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf >= 0x15 {
+        // EAX = denominator, EBX = numerator, ECX = crystal Hz
+        let leaf = unsafe { __cpuid(0x15) };
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64);
+        }
+    }
+    if max_leaf >= 0x16 {
+        // EAX[15:0] = base frequency in MHz
+        let mhz = unsafe { __cpuid(0x16) }.eax & 0xFFFF;
+        if mhz != 0 {
+            return Some(mhz as u64 * 1_000_000);
+        }
+    }
+    None
+}
@@ -0,0 +1,60 @@
+.global breakpoint_entry
+.global debug_entry
+
+// Entry stubs for exceptions whose handlers need (and may change) every
+// register of the interrupted code, i.e. the debugger traps.
+// The pushes below the CPU's interrupt frame form a `TrapFrame`.
+// Neither #BP nor #DB pushes an error code, so the frame is 16 byte aligned.
+// A trap from ring 3 (CS.RPL, at [rsp + 8]) swaps in the kernel's GS base.
+.macro TRAP_ENTRY name, handler
+\name:
+    test qword ptr [rsp + 8], 3
+    jz 1f
+    swapgs
+1:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    // fn handler(frame: &mut TrapFrame)
+    mov rdi, rsp
+    cld
+    call \handler
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+
+    test qword ptr [rsp + 8], 3
+    jz 2f
+    swapgs
+2:
+    iretq
+.endm
+
+TRAP_ENTRY breakpoint_entry, breakpoint_handler
+TRAP_ENTRY debug_entry, debug_handler
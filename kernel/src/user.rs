@@ -0,0 +1,176 @@
+use crate::elf::Program;
+use crate::memory::{self, AddressSpace, USER_SPACE_END};
+use crate::syscall::Errno;
+use alloc::{string::String, vec};
+use core::arch::global_asm;
+use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+// The layout of user address spaces, the built-in test program, access to
+// user memory for syscalls and the keyboard input user programs read. See
+// `process` for running them.
+
+/// Where the built-in test program is loaded and started.
+pub const TEST_PROGRAM_BASE: u64 = 0x40_0000;
+/// Top of every program's stack.
+pub const STACK_TOP: u64 = USER_SPACE_END - 0x1000;
+/// Size of every program's stack.
+pub const STACK_SIZE: u64 = 4096 * 16;
+/// Where every program's heap starts; `brk` moves its end.
+pub const HEAP_START: u64 = 0x1000_0000_0000;
+/// Largest heap `brk` grows to.
+pub const HEAP_MAX_SIZE: u64 = 256 * 1024 * 1024;
+
+// Keyboard input not yet read by any process
+const INPUT_CAPACITY: usize = 256;
+
+global_asm!(include_str!("user_test.asm"));
+global_asm!(include_str!("user_copy.asm"));
+
+unsafe extern "C" {
+    static user_test_start: u8;
+    static user_test_end: u8;
+
+    fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn strncpy_user(dst: *mut u8, src: *const u8, max: usize) -> isize;
+    fn copy_user_access();
+    fn copy_user_fixup();
+    fn strncpy_user_access();
+    fn strncpy_user_fixup();
+}
+
+lazy_static! {
+    static ref INPUT: ArrayQueue<u8> = ArrayQueue::new(INPUT_CAPACITY);
+}
+
+/// The built-in program that tries every syscall, see `user_test.asm`.
+pub fn test_program() -> &'static [u8] {
+    unsafe {
+        let start = &raw const user_test_start;
+        let end = &raw const user_test_end;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Loads flat machine code (such as `test_program`), entered at its first
+/// byte, into a new address space.
+pub fn load_flat(image: &[u8]) -> Result<Program, &'static str> {
+    let mut address_space = AddressSpace::new()?;
+    let base = VirtAddr::new(TEST_PROGRAM_BASE);
+    address_space.map_range(base, image.len() as u64, PageTableFlags::empty())?;
+    address_space.write(base, image)?;
+    Ok(Program {
+        address_space,
+        entry: TEST_PROGRAM_BASE,
+    })
+}
+
+/// Whether `len` bytes at `addr` are user memory of the running process,
+/// and writable if `write` is set.
+pub fn contains(addr: u64, len: u64, write: bool) -> bool {
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    if end > USER_SPACE_END {
+        return false;
+    }
+    if len == 0 {
+        return true;
+    }
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let mut page = addr & !0xFFF;
+    while page < end {
+        match memory::translate_with_flags(VirtAddr::new(page)) {
+            Some((_, flags)) if flags.contains(required) => {}
+            _ => return false,
+        }
+        page += 0x1000;
+    }
+    true
+}
+
+/// Copies `dst.len()` bytes from user address `src` of the running process.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), Errno> {
+    if !contains(src, dst.len() as u64, false) {
+        return Err(Errno::EFAULT);
+    }
+    match unsafe { copy_user(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+/// Copies `src` to user address `dst` of the running process.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), Errno> {
+    if !contains(dst, src.len() as u64, true) {
+        return Err(Errno::EFAULT);
+    }
+    match unsafe { copy_user(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+/// Copies the NUL-terminated UTF-8 string at user address `src`, at most
+/// `max` bytes long without the NUL.
+pub fn strncpy_from_user(src: u64, max: usize) -> Result<String, Errno> {
+    // Its length is unknown, so only the first page is checked up front;
+    // the copy stops at the end of the user half and faults elsewhere
+    if !contains(src, 1, false) {
+        return Err(Errno::EFAULT);
+    }
+    let limit = (max + 1).min((USER_SPACE_END - src) as usize);
+    let mut buffer = vec![0u8; limit];
+    let len = unsafe { strncpy_user(buffer.as_mut_ptr(), src as *const u8, limit) };
+
+    let len = usize::try_from(len).map_err(|_| Errno::EFAULT)?;
+    if len == limit {
+        // No NUL in time
+        return Err(if len > max {
+            Errno::ENAMETOOLONG
+        } else {
+            Errno::EFAULT
+        });
+    }
+    buffer.truncate(len);
+    String::from_utf8(buffer).map_err(|_| Errno::EINVAL)
+}
+
+/// Where to resume after a page fault at `rip`, if it is one of the user
+/// memory accesses in `user_copy.asm`.
+pub fn fixup(rip: u64) -> Option<u64> {
+    let exception_table = [
+        (copy_user_access as *const (), copy_user_fixup as *const ()),
+        (
+            strncpy_user_access as *const (),
+            strncpy_user_fixup as *const (),
+        ),
+    ];
+    exception_table
+        .iter()
+        .find(|(access, _)| *access as u64 == rip)
+        .map(|(_, fixup)| *fixup as u64)
+}
+
+/// Hands a byte of keyboard input to whichever process reads first.
+/// Dropped if none reads fast enough.
+pub fn push_input(byte: u8) {
+    let _ = INPUT.push(byte);
+}
+
+/// Drops keyboard input nobody has read, before a new program takes over
+/// the keyboard.
+pub fn clear_input() {
+    while INPUT.pop().is_some() {}
+}
+
+/// Takes the next byte of keyboard input, for `read`.
+pub fn pop_input() -> Option<u8> {
+    INPUT.pop()
+}
@@ -0,0 +1,44 @@
+.global copy_user
+.global copy_user_access
+.global copy_user_fixup
+.global strncpy_user
+.global strncpy_user_access
+.global strncpy_user_fixup
+
+// The only instructions that touch user memory. The caller checked the
+// range first, but if one of the `*_access` instructions faults anyway,
+// `page_fault_handler` resumes at the matching `*_fixup` label (see the
+// exception table in `user.rs`) instead of panicking.
+
+// fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize
+// Returns the number of bytes NOT copied: 0 unless it faulted.
+copy_user:
+    mov rcx, rdx
+copy_user_access:
+    rep movsb
+copy_user_fixup:
+    // A fault leaves RCX at the bytes still to go
+    mov rax, rcx
+    ret
+
+// fn strncpy_user(dst: *mut u8, src: *const u8, max: usize) -> isize
+// Copies bytes up to and including a NUL, at most `max`. Returns the
+// length of the string without the NUL (`max` if there was none), or -1
+// if it faulted.
+strncpy_user:
+    xor eax, eax
+1:
+    cmp rax, rdx
+    je 2f
+strncpy_user_access:
+    movzx ecx, byte ptr [rsi + rax]
+    mov [rdi + rax], cl
+    test cl, cl
+    je 2f
+    inc rax
+    jmp 1b
+2:
+    ret
+strncpy_user_fixup:
+    mov rax, -1
+    ret
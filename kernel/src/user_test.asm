@@ -0,0 +1,176 @@
+.global user_test_start
+.global user_test_end
+
+// The ring 3 test program run by the shell's `usertest`. `user::load_flat`
+// copies it to `user::TEST_PROGRAM_BASE`, so it only refers to itself
+// RIP-relative. It tries every syscall, prints a line per check and exits
+// with the number of failed checks.
+user_test_start:
+    xor r15, r15
+
+    // write: returns the number of bytes written
+    lea rsi, [rip + .Lbanner]
+    lea rdx, [rip + .Lbanner_end]
+    mov rbx, rdx
+    sub rbx, rsi
+    call .Lprint
+    xor r12, r12
+    cmp rax, rbx
+    sete r12b
+    lea rsi, [rip + .Lname_write]
+    lea rdx, [rip + .Lname_write_end]
+    call .Lreport
+
+    // getpid: some id, never an error
+    mov rax, 4
+    syscall
+    xor r12, r12
+    test rax, rax
+    setns r12b
+    lea rsi, [rip + .Lname_getpid]
+    lea rdx, [rip + .Lname_getpid_end]
+    call .Lreport
+
+    // yield: returns 0
+    mov rax, 3
+    syscall
+    xor r12, r12
+    test rax, rax
+    setz r12b
+    lea rsi, [rip + .Lname_yield]
+    lea rdx, [rip + .Lname_yield_end]
+    call .Lreport
+
+    // sleep(100) takes at least 100 ms by uptime
+    mov rax, 6
+    syscall
+    mov rbx, rax
+    mov rax, 5
+    mov rdi, 100
+    syscall
+    mov rax, 6
+    syscall
+    sub rax, rbx
+    xor r12, r12
+    cmp rax, 100
+    setae r12b
+    lea rsi, [rip + .Lname_sleep]
+    lea rdx, [rip + .Lname_sleep_end]
+    call .Lreport
+
+    // An unknown number gives -ENOSYS
+    mov rax, 999
+    syscall
+    xor r12, r12
+    cmp rax, -38
+    sete r12b
+    lea rsi, [rip + .Lname_enosys]
+    lea rdx, [rip + .Lname_enosys_end]
+    call .Lreport
+
+    // write to a bad fd gives -EBADF
+    mov rax, 1
+    mov rdi, 7
+    lea rsi, [rip + .Lbanner]
+    mov rdx, 1
+    syscall
+    xor r12, r12
+    cmp rax, -9
+    sete r12b
+    lea rsi, [rip + .Lname_ebadf]
+    lea rdx, [rip + .Lname_ebadf_end]
+    call .Lreport
+
+    // write from kernel memory gives -EFAULT
+    mov rax, 1
+    mov rdi, 1
+    movabs rsi, 0xFFFF800000000000
+    mov rdx, 16
+    syscall
+    xor r12, r12
+    cmp rax, -14
+    sete r12b
+    lea rsi, [rip + .Lname_efault]
+    lea rdx, [rip + .Lname_efault_end]
+    call .Lreport
+
+    // read: one key, echoed back
+    lea rsi, [rip + .Lprompt]
+    lea rdx, [rip + .Lprompt_end]
+    call .Lprint
+    sub rsp, 16
+    mov rax, 0
+    mov rdi, 0
+    mov rsi, rsp
+    mov rdx, 1
+    syscall
+    mov rbx, rax
+    mov rsi, rsp
+    lea rdx, [rsp + 1]
+    call .Lprint
+    add rsp, 16
+    lea rsi, [rip + .Lnewline]
+    lea rdx, [rip + .Lnewline_end]
+    call .Lprint
+    xor r12, r12
+    cmp rbx, 1
+    sete r12b
+    lea rsi, [rip + .Lname_read]
+    lea rdx, [rip + .Lname_read_end]
+    call .Lreport
+
+    // exit(failures), which doesn't return
+    mov rax, 2
+    mov rdi, r15
+    syscall
+    ud2
+
+// write(1, rsi, rdx - rsi)
+.Lprint:
+    sub rdx, rsi
+    mov rax, 1
+    mov rdi, 1
+    syscall
+    ret
+
+// Prints the check name at rsi..rdx and whether it passed (r12 != 0)
+.Lreport:
+    call .Lprint
+    test r12, r12
+    jz 1f
+    lea rsi, [rip + .Lok]
+    lea rdx, [rip + .Lok_end]
+    jmp .Lprint
+1:
+    inc r15
+    lea rsi, [rip + .Lfailed]
+    lea rdx, [rip + .Lfailed_end]
+    jmp .Lprint
+
+.Lbanner: .ascii "Hello from ring 3\n"
+.Lbanner_end:
+.Lprompt: .ascii "Press a key: "
+.Lprompt_end:
+.Lnewline: .ascii "\n"
+.Lnewline_end:
+.Lok: .ascii ": ok\n"
+.Lok_end:
+.Lfailed: .ascii ": FAILED\n"
+.Lfailed_end:
+.Lname_write: .ascii "write"
+.Lname_write_end:
+.Lname_getpid: .ascii "getpid"
+.Lname_getpid_end:
+.Lname_yield: .ascii "yield"
+.Lname_yield_end:
+.Lname_sleep: .ascii "sleep and uptime"
+.Lname_sleep_end:
+.Lname_enosys: .ascii "unknown syscall is ENOSYS"
+.Lname_enosys_end:
+.Lname_ebadf: .ascii "bad fd is EBADF"
+.Lname_ebadf_end:
+.Lname_efault: .ascii "kernel pointer is EFAULT"
+.Lname_efault_end:
+.Lname_read: .ascii "read"
+.Lname_read_end:
+user_test_end:
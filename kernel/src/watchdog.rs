@@ -0,0 +1,174 @@
+use crate::backtrace::Backtrace;
+use crate::interrupts::{self, IRQ_TIMER};
+use crate::percpu::MAX_CPUS;
+use crate::serial::_print_unlocked;
+use crate::time::{self, Duration, Instant};
+use crate::{apic, gdb, panic, percpu};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use log::{info, warn};
+use x86_64::structures::idt::InterruptStackFrame;
+
+// Soft lockup: a task poll that doesn't return. Noticed by the timer tick of
+// the stuck CPU itself, which still takes interrupts.
+// Hard lockup: a CPU whose timer ticks stop (spinning with interrupts off).
+// A second CPU watches the heartbeats on its own local APIC timer and sends
+// the stuck one an NMI, which gets through `cli`, to report where it is.
+// Only the BSP takes the PIT tick, so only the BSP has a heartbeat: a hard
+// lockup on an AP goes unnoticed.
+// Both reports come from interrupt context and only use `_print_unlocked`.
+
+/// A poll running for longer than this is a soft lockup.
+pub const SOFT_LOCKUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// A CPU without a timer tick for this long is in a hard lockup.
+pub const HARD_LOCKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Vector of the watcher CPU's local APIC timer.
+pub const WATCHDOG_VECTOR: u8 = 0xF0;
+const WATCHER_CPU: usize = 1;
+const WATCHER_PERIOD: Duration = Duration::from_millis(500);
+
+// `task_polls` count of the poll last reported as a soft lockup, so it's
+// reported once. Not `poll_started`, which moves when the executor's thread
+// is switched back in.
+static SOFT_REPORTED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+// What the watcher last saw of each CPU
+static LAST_HEARTBEAT: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static LAST_PROGRESS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static HARD_REPORTED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+// Tells a CPU that an NMI came from the watcher
+static NMI_REQUESTED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Hooks the watchdog into the timer. Call after `smp::init`.
+pub fn init() {
+    interrupts::register_irq(IRQ_TIMER, tick);
+
+    if percpu::get(WATCHER_CPU).is_some() {
+        info!(
+            "Watchdog: soft lockups after {}s, hard lockups of CPU 0 after {}s (watched by CPU {})",
+            SOFT_LOCKUP_TIMEOUT.as_secs(),
+            HARD_LOCKUP_TIMEOUT.as_secs(),
+            WATCHER_CPU
+        );
+    } else {
+        warn!(
+            "Watchdog: soft lockups after {}s, hard lockup detection needs a second CPU",
+            SOFT_LOCKUP_TIMEOUT.as_secs()
+        );
+    }
+}
+
+/// Called by every AP once it is up; one of them becomes the watcher.
+pub fn init_ap(cpu_id: usize) {
+    if cpu_id == WATCHER_CPU {
+        apic::start_periodic_timer(WATCHDOG_VECTOR, WATCHER_PERIOD);
+    }
+}
+
+/// Resets the calling CPU's lockup timers, after it was legitimately stopped (in the debugger).
+pub fn touch() {
+    let cpu = percpu!();
+    if cpu.poll_started.load(Ordering::Relaxed) != 0 {
+        cpu.poll_started.store(time::tsc_now(), Ordering::Relaxed);
+    }
+    cpu.heartbeat.fetch_add(1, Ordering::Relaxed);
+}
+
+// Stopped in the debugger or halted by a panic is not a lockup
+fn suppressed() -> bool {
+    gdb::in_session() || panic::is_panicking()
+}
+
+// Runs on every timer IRQ
+fn tick() {
+    let cpu = percpu!();
+    cpu.heartbeat.fetch_add(1, Ordering::Relaxed);
+
+    let started = cpu.poll_started.load(Ordering::Relaxed);
+    if started == 0 || suppressed() {
+        return;
+    }
+    let stuck_for = Instant::from_cycles(started).elapsed();
+    let poll = cpu.stats.task_polls.load(Ordering::Relaxed);
+    if stuck_for < SOFT_LOCKUP_TIMEOUT
+        || SOFT_REPORTED[cpu.cpu_id].swap(poll, Ordering::Relaxed) == poll
+    {
+        return;
+    }
+
+    // The stuck task may be holding WRITER or the serial port, so not the log
+    let rip = cpu.interrupted_rip.load(Ordering::Relaxed);
+    let rbp = cpu.interrupted_rbp.load(Ordering::Relaxed);
+    _print_unlocked(format_args!(
+        "Soft lockup on CPU {}: task {} stuck for {}s at {:#x}\nBacktrace:\n{}\n",
+        cpu.cpu_id,
+        TaskName(cpu.current_task.load(Ordering::Relaxed)),
+        stuck_for.as_secs(),
+        rip,
+        Backtrace::from_frame_pointer(Some(rip), rbp)
+    ));
+}
+
+/// Runs on the watcher CPU's timer: sends an NMI to every CPU whose heartbeat stopped.
+pub fn check_hard_lockups() {
+    if suppressed() {
+        return;
+    }
+
+    let this_cpu = percpu!().cpu_id;
+    let now = time::tsc_now();
+    for cpu_id in (0..MAX_CPUS).filter(|id| *id != this_cpu) {
+        let Some(cpu) = percpu::get(cpu_id) else {
+            continue;
+        };
+        // Only CPUs taking timer ticks (the BSP) have a heartbeat to watch
+        let heartbeat = cpu.heartbeat.load(Ordering::Relaxed);
+        if heartbeat == 0 {
+            continue;
+        }
+
+        if LAST_HEARTBEAT[cpu_id].swap(heartbeat, Ordering::Relaxed) != heartbeat {
+            LAST_PROGRESS[cpu_id].store(now, Ordering::Relaxed);
+            HARD_REPORTED[cpu_id].store(false, Ordering::Relaxed);
+            continue;
+        }
+
+        let stalled_since = Instant::from_cycles(LAST_PROGRESS[cpu_id].load(Ordering::Relaxed));
+        if stalled_since.elapsed() >= HARD_LOCKUP_TIMEOUT
+            && !HARD_REPORTED[cpu_id].swap(true, Ordering::Relaxed)
+        {
+            NMI_REQUESTED[cpu_id].store(true, Ordering::SeqCst);
+            apic::send_nmi(cpu.apic_id);
+        }
+    }
+}
+
+/// Called by the NMI handler with the frame pointer of the interrupted code.
+pub fn handle_nmi(stack_frame: &InterruptStackFrame, rbp: u64) {
+    let cpu = percpu!();
+    if !NMI_REQUESTED[cpu.cpu_id].swap(false, Ordering::SeqCst) {
+        _print_unlocked(format_args!("Unexpected NMI on CPU {}\n", cpu.cpu_id));
+        return;
+    }
+
+    // This CPU may be spinning on any lock, the serial port and the log included
+    let rip = stack_frame.instruction_pointer.as_u64();
+    _print_unlocked(format_args!(
+        "Hard lockup on CPU {}: task {} stuck with interrupts disabled at {:#x}\nBacktrace:\n{}\n",
+        cpu.cpu_id,
+        TaskName(cpu.current_task.load(Ordering::Relaxed)),
+        rip,
+        Backtrace::from_frame_pointer(Some(rip), rbp)
+    ));
+}
+
+// A `current_task` value for display
+struct TaskName(u64);
+
+impl core::fmt::Display for TaskName {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            percpu::NONE => write!(f, "(none)"),
+            id => write!(f, "{}", id),
+        }
+    }
+}
@@ -1,13 +1,18 @@
 use ovmf_prebuilt::{Arch, FileType, Prebuilt, Source};
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{Command, exit};
 
 const DISK_SIZE_MB: usize = 64;
 const INPUT_DIR: &str = "disk";
 const IMAGE_FILE: &str = "user_disk.img";
 const OUTPUT_DIR: &str = "disk_modified";
+const GDB_PORT: u16 = 4444;
+// Its examples are the user programs put on the disk
+const USER_CRATE: &str = "libsamux";
+const USER_EXAMPLES_DIR: &str = "user/libsamux/examples";
+const USER_TARGET: &str = "x86_64-unknown-none";
 
 fn main() {
     let uefi_path = env!("UEFI_PATH");
@@ -18,16 +23,24 @@ fn main() {
         Some("uefi") => true,
         Some("bios") => false,
         _ => {
-            println!("Usage: cargo run -- [uefi|bios]");
+            println!("Usage: cargo run -- [uefi|bios] [gdb]");
             exit(1);
         }
     };
+    // Expose COM2, where the kernel's GDB stub listens, on a TCP port
+    let gdb = args.get(2).map(|s| s.as_str()) == Some("gdb");
 
     // Prepare the Disk Image
     prepare_disk_image();
 
     let mut cmd = Command::new("qemu-system-x86_64");
     cmd.arg("-serial").arg("mon:stdio");
+    if gdb {
+        cmd.arg("-serial")
+            .arg(format!("tcp::{GDB_PORT},server,nowait"));
+        println!("Run `gdb` in the shell, then `target remote :{GDB_PORT}` in GDB");
+    }
+    cmd.arg("-smp").arg("4");
     cmd.arg("-device")
         .arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
 
@@ -92,6 +105,8 @@ fn prepare_disk_image() {
         );
     }
 
+    let programs = build_user_programs();
+
     println!("Copying files from '{}' to disk image...", INPUT_DIR);
 
     // pass each file individually because Rust doesn't expand "disk/*"
@@ -104,6 +119,10 @@ fn prepare_disk_image() {
         mcopy_cmd.arg(entry.path());
         has_files = true;
     }
+    for program in &programs {
+        mcopy_cmd.arg(program);
+        has_files = true;
+    }
 
     if has_files {
         // Destination is root of image
@@ -118,6 +137,39 @@ fn prepare_disk_image() {
     }
 }
 
+/// Builds the example programs of the user runtime and returns their paths.
+fn build_user_programs() -> Vec<PathBuf> {
+    println!("Building user programs...");
+
+    // Set when started by `cargo run`
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let status = Command::new(cargo)
+        .args(["build", "--release", "--examples"])
+        .args(["-p", USER_CRATE, "--target", USER_TARGET])
+        .status()
+        .expect("failed to run cargo");
+    if !status.success() {
+        panic!("failed to build the user programs");
+    }
+
+    let target_dir = env::var("CARGO_TARGET_DIR").unwrap_or_else(|_| "target".to_string());
+    let output_dir = Path::new(&target_dir)
+        .join(USER_TARGET)
+        .join("release")
+        .join("examples");
+
+    let mut programs = Vec::new();
+    for entry in fs::read_dir(USER_EXAMPLES_DIR).expect("failed to read examples dir") {
+        let path = entry.expect("failed to read directory entry").path();
+        if path.extension().is_some_and(|ext| ext == "rs") {
+            // Named after the example, so that has to be an 8.3 name
+            let name = path.file_stem().unwrap();
+            programs.push(output_dir.join(name));
+        }
+    }
+    programs
+}
+
 fn extract_disk_image() {
     println!("Extracting disk state to '{}'...", OUTPUT_DIR);
     let output_path = Path::new(OUTPUT_DIR);
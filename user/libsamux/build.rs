@@ -0,0 +1,7 @@
+fn main() {
+    // The kernel loads statically linked executables at a fixed address
+    // (ET_EXEC), not the position independent ones this target makes by
+    // default
+    println!("cargo:rustc-link-arg-examples=--no-pie");
+    println!("cargo:rustc-link-arg-examples=--image-base=0x400000");
+}
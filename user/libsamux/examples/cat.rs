@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+
+use libsamux::syscall::{self, O_RDONLY, STDIN, STDOUT};
+use libsamux::{Errno, env, eprintln};
+
+libsamux::entry!(main);
+
+// Copies the named files to the console, or what is typed until a `.`
+fn main() -> i32 {
+    let mut status = 0;
+    let mut files = env::args().skip(1).peekable();
+    if files.peek().is_none()
+        && let Err(e) = copy(STDIN, true)
+    {
+        eprintln!("cat: {}", e);
+        status = 1;
+    }
+
+    for name in files {
+        let result = syscall::open(name, O_RDONLY).and_then(|fd| {
+            let copied = copy(fd, false);
+            syscall::close(fd)?;
+            copied
+        });
+        if let Err(e) = result {
+            eprintln!("cat: {}: {}", name, e);
+            status = 1;
+        }
+    }
+    status
+}
+
+fn copy(fd: u64, console: bool) -> Result<(), Errno> {
+    let mut buffer = [0u8; 512];
+    loop {
+        let count = syscall::read(fd, &mut buffer)?;
+        if count == 0 || (console && buffer[..count].contains(&b'.')) {
+            return Ok(());
+        }
+        syscall::write_all(STDOUT, &buffer[..count])?;
+    }
+}
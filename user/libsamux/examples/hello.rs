@@ -0,0 +1,26 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use libsamux::{env, println, syscall};
+
+libsamux::entry!(main);
+
+fn main() -> i32 {
+    println!("Hello from pid {}!", syscall::getpid());
+
+    let args: Vec<&str> = env::args().collect();
+    println!("{} argument(s): {:?}", args.len(), args);
+
+    let mut text = String::new();
+    for (i, arg) in args.iter().skip(1).enumerate() {
+        if i > 0 {
+            text.push(' ');
+        }
+        text.push_str(arg);
+    }
+    println!("Up for {} ms, echoing \"{}\"", syscall::uptime(), text);
+    0
+}
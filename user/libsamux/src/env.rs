@@ -0,0 +1,40 @@
+use core::ffi::CStr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+// The arguments the program was started with. The first is its name.
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+
+// Called by `_start` before `main`
+pub(crate) unsafe fn init(argc: usize, argv: *const *const u8) {
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv as *mut _, Ordering::Relaxed);
+}
+
+/// The arguments, skipping any that aren't UTF-8.
+pub fn args() -> Args {
+    Args { index: 0 }
+}
+
+pub struct Args {
+    index: usize,
+}
+
+impl Iterator for Args {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        let argc = ARGC.load(Ordering::Relaxed);
+        let argv = ARGV.load(Ordering::Relaxed);
+        while self.index < argc {
+            // The kernel keeps the strings on the stack for as long as we run
+            let arg = unsafe { CStr::from_ptr(*argv.add(self.index) as *const _) };
+            self.index += 1;
+            if let Ok(arg) = arg.to_str() {
+                return Some(arg);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,56 @@
+use crate::syscall;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use linked_list_allocator::LockedHeap;
+
+// The global allocator. It starts out empty and grows the heap with `brk`
+// whenever an allocation doesn't fit.
+
+/// The least the heap grows by at a time.
+pub const GROW_SIZE: usize = 64 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: BrkAllocator = BrkAllocator(LockedHeap::empty());
+
+struct BrkAllocator(LockedHeap);
+
+unsafe impl GlobalAlloc for BrkAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.0.lock();
+        if let Ok(ptr) = heap.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        let end = if heap.size() == 0 {
+            match syscall::brk(0) {
+                Ok(end) => end,
+                Err(_) => return ptr::null_mut(),
+            }
+        } else {
+            heap.top() as u64
+        };
+        // Enough for the allocation, however the free space is aligned
+        let grow = (layout.size() + layout.align())
+            .max(GROW_SIZE)
+            .next_multiple_of(4096);
+        let Ok(new_end) = syscall::brk(end + grow as u64) else {
+            return ptr::null_mut();
+        };
+
+        unsafe {
+            if heap.size() == 0 {
+                heap.init(end as *mut u8, (new_end - end) as usize);
+            } else {
+                heap.extend((new_end - end) as usize);
+            }
+        }
+        heap.allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), |ptr| ptr.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            unsafe { self.0.lock().deallocate(ptr, layout) };
+        }
+    }
+}
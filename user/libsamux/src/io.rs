@@ -0,0 +1,40 @@
+use crate::syscall::{self, STDERR, STDOUT};
+use core::fmt;
+
+// Formatted output to the console, through descriptors 1 and 2.
+
+/// A descriptor to format into.
+pub struct Writer(pub u64);
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        syscall::write_all(self.0, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut Writer(STDOUT), args);
+}
+
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut Writer(STDERR), args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::io::_eprint(format_args!("\n")));
+    ($($arg:tt)*) => ($crate::io::_eprint(format_args!("{}\n", format_args!($($arg)*))));
+}
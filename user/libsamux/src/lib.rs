@@ -0,0 +1,27 @@
+#![no_std]
+
+extern crate alloc;
+
+// The runtime of Samux user programs: the entry point, syscalls, a heap and
+// console output. A program is a `#![no_std]`, `#![no_main]` binary that
+// names its main function with `entry!`:
+//
+//     #![no_std]
+//     #![no_main]
+//
+//     use libsamux::println;
+//
+//     libsamux::entry!(main);
+//
+//     fn main() -> i32 {
+//         println!("Hello from ring 3!");
+//         0
+//     }
+
+pub mod env;
+pub mod heap;
+pub mod io;
+pub mod start;
+pub mod syscall;
+
+pub use syscall::Errno;
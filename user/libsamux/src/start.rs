@@ -0,0 +1,54 @@
+use crate::{env, eprintln, syscall};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+
+// Where the kernel enters a program, with the stack pointer at argc and
+// the argv pointers above it (see `push_args` in the kernel's `process`).
+
+global_asm!(
+    ".global _start",
+    "_start:",
+    // Ends frame pointer chains
+    "xor ebp, ebp",
+    "mov rdi, rsp",
+    "and rsp, -16",
+    "call {start}",
+    "ud2",
+    start = sym start,
+);
+
+/// Exit status of a program that panicked.
+pub const EXIT_PANIC: i64 = 101;
+
+unsafe extern "Rust" {
+    // Defined by `entry!`
+    fn __samux_main() -> i32;
+}
+
+/// Names the main function of a program: a `fn() -> i32` returning its
+/// exit status.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[unsafe(no_mangle)]
+        fn __samux_main() -> i32 {
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
+
+unsafe extern "C" fn start(stack: *const u64) -> ! {
+    unsafe {
+        let argc = *stack as usize;
+        env::init(argc, stack.add(1) as *const *const u8);
+        let status = __samux_main();
+        syscall::exit(status as i64);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{}", info);
+    syscall::exit(EXIT_PANIC);
+}
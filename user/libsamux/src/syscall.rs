@@ -0,0 +1,199 @@
+use core::arch::asm;
+use core::fmt;
+
+// Typed wrappers around the kernel's syscalls. The numbers, flags and
+// errors mirror `kernel/src/syscall.rs` and `kernel/src/file.rs`.
+
+pub const SYS_READ: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_EXIT: u64 = 2;
+pub const SYS_YIELD: u64 = 3;
+pub const SYS_GETPID: u64 = 4;
+pub const SYS_SLEEP: u64 = 5;
+pub const SYS_UPTIME: u64 = 6;
+pub const SYS_OPEN: u64 = 7;
+pub const SYS_CLOSE: u64 = 8;
+pub const SYS_LSEEK: u64 = 9;
+pub const SYS_FSTAT: u64 = 10;
+pub const SYS_DUP2: u64 = 11;
+pub const SYS_BRK: u64 = 12;
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+// `open` flags
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+pub const O_CREAT: u32 = 0o100;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+
+// `lseek` origins
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+// `Stat::kind`
+pub const KIND_CONSOLE: u32 = 1;
+pub const KIND_FILE: u32 = 2;
+
+/// Longest path `open` takes.
+pub const PATH_MAX: usize = 255;
+
+/// What `fstat` returns.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+    pub kind: u32,
+    pub flags: u32,
+    pub size: u64,
+}
+
+/// Why a syscall failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub i64);
+
+impl Errno {
+    pub const ENOENT: Errno = Errno(2);
+    pub const EIO: Errno = Errno(5);
+    pub const EBADF: Errno = Errno(9);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EINVAL: Errno = Errno(22);
+    pub const EMFILE: Errno = Errno(24);
+    pub const EFBIG: Errno = Errno(27);
+    pub const ESPIPE: Errno = Errno(29);
+    pub const ENAMETOOLONG: Errno = Errno(36);
+    pub const ENOSYS: Errno = Errno(38);
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match *self {
+            Errno::ENOENT => "no such file",
+            Errno::EIO => "disk error",
+            Errno::EBADF => "bad file descriptor",
+            Errno::ENOMEM => "out of memory",
+            Errno::EFAULT => "bad address",
+            Errno::EINVAL => "invalid argument",
+            Errno::EMFILE => "too many open files",
+            Errno::EFBIG => "file too large",
+            Errno::ESPIPE => "not seekable",
+            Errno::ENAMETOOLONG => "name too long",
+            Errno::ENOSYS => "no such syscall",
+            Errno(errno) => return write!(f, "error {}", errno),
+        };
+        f.write_str(description)
+    }
+}
+
+/// Makes syscall `number`. The kernel reads six arguments, unused ones
+/// don't matter.
+///
+/// # Safety
+/// The arguments must be what the syscall expects, pointers included.
+pub unsafe fn syscall(number: u64, args: [u64; 6]) -> Result<u64, Errno> {
+    let result: i64;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") number as i64 => result,
+            in("rdi") args[0],
+            in("rsi") args[1],
+            in("rdx") args[2],
+            in("r10") args[3],
+            in("r8") args[4],
+            in("r9") args[5],
+            // The CPU keeps RIP and RFLAGS here
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    if result < 0 {
+        Err(Errno(-result))
+    } else {
+        Ok(result as u64)
+    }
+}
+
+pub fn read(fd: u64, buffer: &mut [u8]) -> Result<usize, Errno> {
+    let args = [fd, buffer.as_mut_ptr() as u64, buffer.len() as u64, 0, 0, 0];
+    unsafe { syscall(SYS_READ, args) }.map(|count| count as usize)
+}
+
+pub fn write(fd: u64, data: &[u8]) -> Result<usize, Errno> {
+    let args = [fd, data.as_ptr() as u64, data.len() as u64, 0, 0, 0];
+    unsafe { syscall(SYS_WRITE, args) }.map(|count| count as usize)
+}
+
+/// Writes all of `data`, however many `write`s it takes.
+pub fn write_all(fd: u64, mut data: &[u8]) -> Result<(), Errno> {
+    while !data.is_empty() {
+        let count = write(fd, data)?;
+        data = &data[count..];
+    }
+    Ok(())
+}
+
+pub fn exit(status: i64) -> ! {
+    let _ = unsafe { syscall(SYS_EXIT, [status as u64, 0, 0, 0, 0, 0]) };
+    unreachable!("exit returned");
+}
+
+pub fn yield_now() {
+    let _ = unsafe { syscall(SYS_YIELD, [0; 6]) };
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall(SYS_GETPID, [0; 6]) }.unwrap_or(0)
+}
+
+pub fn sleep(ms: u64) {
+    let _ = unsafe { syscall(SYS_SLEEP, [ms, 0, 0, 0, 0, 0]) };
+}
+
+/// Milliseconds since boot.
+pub fn uptime() -> u64 {
+    unsafe { syscall(SYS_UPTIME, [0; 6]) }.unwrap_or(0)
+}
+
+/// Opens `path` with `O_*` flags. Returns the descriptor.
+pub fn open(path: &str, flags: u32) -> Result<u64, Errno> {
+    // The kernel wants it NUL-terminated
+    let mut buffer = [0u8; PATH_MAX + 1];
+    if path.len() > PATH_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    buffer[..path.len()].copy_from_slice(path.as_bytes());
+    unsafe { syscall(SYS_OPEN, [buffer.as_ptr() as u64, flags as u64, 0, 0, 0, 0]) }
+}
+
+pub fn close(fd: u64) -> Result<(), Errno> {
+    unsafe { syscall(SYS_CLOSE, [fd, 0, 0, 0, 0, 0]) }.map(|_| ())
+}
+
+/// Moves the offset of `fd` by `offset` from `whence`. Returns the new
+/// offset.
+pub fn lseek(fd: u64, offset: i64, whence: u64) -> Result<u64, Errno> {
+    unsafe { syscall(SYS_LSEEK, [fd, offset as u64, whence, 0, 0, 0]) }
+}
+
+pub fn fstat(fd: u64) -> Result<Stat, Errno> {
+    let mut stat = Stat::default();
+    let args = [fd, &raw mut stat as u64, 0, 0, 0, 0];
+    unsafe { syscall(SYS_FSTAT, args) }.map(|_| stat)
+}
+
+/// Makes `new` refer to the file of `old`.
+pub fn dup2(old: u64, new: u64) -> Result<u64, Errno> {
+    unsafe { syscall(SYS_DUP2, [old, new, 0, 0, 0, 0]) }
+}
+
+/// Grows the heap to end at `end`. Returns the new end; `brk(0)` returns the
+/// current one.
+pub fn brk(end: u64) -> Result<u64, Errno> {
+    unsafe { syscall(SYS_BRK, [end, 0, 0, 0, 0, 0]) }
+}